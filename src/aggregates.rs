@@ -0,0 +1,773 @@
+use std::{cmp::Ordering, collections::HashSet};
+
+use ndc_client::models;
+use serde_json::{Number, Value};
+
+use crate::coercion::Representation;
+
+/// Aggregate functions the proxy can compute itself from fetched rows, when the v3 target does not provide them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatedFunction {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    StddevPop,
+    StddevSamp,
+    VarPop,
+    VarSamp,
+    Longest,
+    Shortest,
+    /// Distinct column counts, which v2 expresses as a column count rather than a function
+    CountDistinct,
+}
+
+/// v2 aggregate function names, and the function we compute for each
+static EMULATED_FUNCTIONS: &[(&str, EmulatedFunction)] = &[
+    ("sum", EmulatedFunction::Sum),
+    ("avg", EmulatedFunction::Avg),
+    ("min", EmulatedFunction::Min),
+    ("max", EmulatedFunction::Max),
+    ("stddev", EmulatedFunction::StddevSamp),
+    ("stddev_pop", EmulatedFunction::StddevPop),
+    ("stddev_samp", EmulatedFunction::StddevSamp),
+    ("variance", EmulatedFunction::VarSamp),
+    ("var_pop", EmulatedFunction::VarPop),
+    ("var_samp", EmulatedFunction::VarSamp),
+    ("longest", EmulatedFunction::Longest),
+    ("shortest", EmulatedFunction::Shortest),
+];
+
+/// Preferred names for the scalar type of statistical aggregates, if the target declares them
+static FLOAT_TYPE_NAMES: &[&str] = &["Float64", "float64", "Float", "float", "Double", "double"];
+
+impl EmulatedFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        EMULATED_FUNCTIONS
+            .iter()
+            .find(|(function_name, _)| *function_name == name)
+            .map(|(_, function)| *function)
+    }
+
+    /// Whether the function can be computed over values of the representation
+    pub fn applies_to(self, representation: Representation) -> bool {
+        let is_numeric = matches!(
+            representation,
+            Representation::Integer
+                | Representation::BigInteger
+                | Representation::Float
+                | Representation::Decimal
+        );
+        match self {
+            Self::Sum
+            | Self::Avg
+            | Self::StddevPop
+            | Self::StddevSamp
+            | Self::VarPop
+            | Self::VarSamp => is_numeric,
+            Self::Min | Self::Max => {
+                is_numeric
+                    || matches!(
+                        representation,
+                        Representation::String | Representation::Date | Representation::DateTime
+                    )
+            }
+            Self::Longest | Self::Shortest => representation == Representation::String,
+            // JSON values that are equal may be serialized differently, so they cannot be compared as returned
+            Self::CountDistinct => representation != Representation::Json,
+        }
+    }
+
+    /// The scalar type of the result of the function, when applied to a column of the given scalar type
+    fn result_type(self, scalar_type: &str, schema: &models::SchemaResponse) -> Option<String> {
+        match self {
+            Self::Sum | Self::Min | Self::Max | Self::Longest | Self::Shortest => {
+                Some(scalar_type.to_owned())
+            }
+            Self::Avg | Self::StddevPop | Self::StddevSamp | Self::VarPop | Self::VarSamp => {
+                float_scalar_type(schema)
+            }
+            // counts are not advertised as aggregate functions
+            Self::CountDistinct => None,
+        }
+    }
+
+    /// Compute the function over the values of a column with the given representation.
+    /// Nulls are ignored, and the result is null if there are no values
+    pub fn compute(self, values: &[Value], representation: Representation) -> Value {
+        let values: Vec<&Value> = values.iter().filter(|value| !value.is_null()).collect();
+
+        match self {
+            Self::Sum => sum(&values, representation),
+            Self::Avg => float(average(&values, representation)),
+            Self::Min => extreme(&values, representation, Ordering::Less),
+            Self::Max => extreme(&values, representation, Ordering::Greater),
+            Self::StddevPop => float(variance(&numbers(&values), false).map(f64::sqrt)),
+            Self::StddevSamp => float(variance(&numbers(&values), true).map(f64::sqrt)),
+            Self::VarPop => float(variance(&numbers(&values), false)),
+            Self::VarSamp => float(variance(&numbers(&values), true)),
+            Self::Longest => by_length(&values, Ordering::Greater),
+            Self::Shortest => by_length(&values, Ordering::Less),
+            Self::CountDistinct => {
+                let distinct: HashSet<String> =
+                    values.iter().map(|value| value.to_string()).collect();
+                Value::Number(distinct.len().into())
+            }
+        }
+    }
+}
+
+/// The aggregate functions the proxy can emulate for a scalar type, which the target does not already provide, with their result types
+pub fn emulated_aggregate_functions(
+    schema: &models::SchemaResponse,
+    scalar_type: &str,
+) -> Vec<(String, String)> {
+    let representation = match Representation::of(scalar_type) {
+        Some(representation) => representation,
+        None => return vec![],
+    };
+    let native_functions = schema
+        .scalar_types
+        .get(scalar_type)
+        .map(|scalar_type| &scalar_type.aggregate_functions);
+
+    EMULATED_FUNCTIONS
+        .iter()
+        .filter(|(name, _)| {
+            !native_functions.map_or(false, |functions| functions.contains_key(*name))
+        })
+        .filter(|(_, function)| function.applies_to(representation))
+        .filter_map(|(name, function)| {
+            function
+                .result_type(scalar_type, schema)
+                .map(|result_type| (name.to_string(), result_type))
+        })
+        .collect()
+}
+
+/// The emulated function and its result type, if the function is not provided by the target but can be emulated for the scalar type
+pub fn emulated_aggregate_function(
+    schema: &models::SchemaResponse,
+    scalar_type: &str,
+    function: &str,
+) -> Option<(EmulatedFunction, String)> {
+    emulated_aggregate_functions(schema, scalar_type)
+        .into_iter()
+        .find(|(name, _)| name == function)
+        .and_then(|(name, result_type)| {
+            EmulatedFunction::from_name(&name).map(|function| (function, result_type))
+        })
+}
+
+fn float_scalar_type(schema: &models::SchemaResponse) -> Option<String> {
+    FLOAT_TYPE_NAMES
+        .iter()
+        .find(|name| schema.scalar_types.contains_key(**name))
+        .map(|name| name.to_string())
+        .or_else(|| {
+            schema
+                .scalar_types
+                .keys()
+                .find(|name| Representation::of(name) == Some(Representation::Float))
+                .cloned()
+        })
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => string.parse().ok(),
+        _ => None,
+    }
+}
+
+fn numbers(values: &[&Value]) -> Vec<f64> {
+    values.iter().filter_map(|value| number(value)).collect()
+}
+
+fn float(value: Option<f64>) -> Value {
+    value
+        .and_then(Number::from_f64)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn mean(numbers: &[f64]) -> Option<f64> {
+    if numbers.is_empty() {
+        None
+    } else {
+        Some(numbers.iter().sum::<f64>() / numbers.len() as f64)
+    }
+}
+
+fn variance(numbers: &[f64], sample: bool) -> Option<f64> {
+    let mean = mean(numbers)?;
+    let degrees_of_freedom = if sample {
+        numbers.len().checked_sub(1).filter(|n| *n > 0)?
+    } else {
+        numbers.len()
+    };
+    let squared_deviations: f64 = numbers.iter().map(|n| (n - mean).powi(2)).sum();
+    Some(squared_deviations / degrees_of_freedom as f64)
+}
+
+/// Whether values are summed exactly, as integers or decimals, rather than as floating point numbers
+fn is_exact(representation: Representation) -> bool {
+    matches!(
+        representation,
+        Representation::Integer | Representation::BigInteger | Representation::Decimal
+    )
+}
+
+/// Sum integers and decimals exactly, falling back to floating point for floats, and for sums too large to represent exactly
+fn sum(values: &[&Value], representation: Representation) -> Value {
+    if values.is_empty() {
+        return Value::Null;
+    }
+
+    match exact_sum(values).filter(|_| is_exact(representation)) {
+        // decimals are represented as strings on both sides, so they keep their precision
+        Some(sum) if representation == Representation::Decimal => Value::String(sum.to_string()),
+        Some(Decimal { mantissa, scale: 0 }) => match i64::try_from(mantissa) {
+            Ok(sum) => Value::Number(sum.into()),
+            Err(_) => Value::String(mantissa.to_string()),
+        },
+        _ => float(Some(numbers(values).iter().sum())),
+    }
+}
+
+/// The mean of the values, dividing their exact sum for integers and decimals so the result is only rounded once
+fn average(values: &[&Value], representation: Representation) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    match exact_sum(values).filter(|_| is_exact(representation)) {
+        Some(sum) => Some(sum.to_f64() / values.len() as f64),
+        None => mean(&numbers(values)),
+    }
+}
+
+fn exact_sum(values: &[&Value]) -> Option<Decimal> {
+    values.iter().try_fold(Decimal::ZERO, |sum, value| {
+        sum.checked_add(Decimal::parse(value)?)
+    })
+}
+
+/// The smallest or largest value. Numeric columns are compared numerically, and anything else as strings.
+/// Values that cannot be compared in the column's representation, such as non numeric strings in a numeric column, are ignored.
+fn extreme(values: &[&Value], representation: Representation, ordering: Ordering) -> Value {
+    let compare = |value: &Value, other: &Value| match representation {
+        Representation::Integer | Representation::BigInteger | Representation::Decimal => {
+            Some(Decimal::parse(value)?.compare(Decimal::parse(other)?))
+        }
+        Representation::Float => number(value)?.partial_cmp(&number(other)?),
+        _ => Some(value.as_str()?.cmp(other.as_str()?)),
+    };
+
+    values
+        .iter()
+        .copied()
+        .filter(|value| compare(value, value).is_some())
+        .reduce(|current, value| {
+            if compare(value, current) == Some(ordering) {
+                value
+            } else {
+                current
+            }
+        })
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+fn by_length(values: &[&Value], ordering: Ordering) -> Value {
+    values
+        .iter()
+        .filter_map(|value| value.as_str())
+        .reduce(|current, value| {
+            if value.chars().count().cmp(&current.chars().count()) == ordering {
+                value
+            } else {
+                current
+            }
+        })
+        .map(|value| Value::String(value.to_owned()))
+        .unwrap_or(Value::Null)
+}
+
+/// An exact decimal number: the mantissa divided by 10 to the power of the scale.
+/// Integer and decimal columns are summed and compared with these, so values beyond the precision of a float are exact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    const ZERO: Self = Self {
+        mantissa: 0,
+        scale: 0,
+    };
+
+    /// Parse a JSON number, or a number in a string, as connectors represent big integers and decimals.
+    /// Fails for numbers with more than 38 significant digits.
+    fn parse(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => Self::from_str(&number.to_string()),
+            Value::String(string) => Self::from_str(string.trim()),
+            _ => None,
+        }
+    }
+
+    fn from_str(string: &str) -> Option<Self> {
+        let (string, exponent) = match string.split_once(['e', 'E']) {
+            Some((string, exponent)) => (string, exponent.parse::<i32>().ok()?),
+            None => (string, 0),
+        };
+        let (negative, digits) = match string.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, string.strip_prefix('+').unwrap_or(string)),
+        };
+        let (integral, fractional) = digits.split_once('.').unwrap_or((digits, ""));
+        if integral.is_empty() && fractional.is_empty()
+            || !integral
+                .bytes()
+                .chain(fractional.bytes())
+                .all(|digit| digit.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let mut mantissa: i128 = 0;
+        for digit in integral.bytes().chain(fractional.bytes()) {
+            mantissa = mantissa
+                .checked_mul(10)?
+                .checked_add(i128::from(digit - b'0'))?;
+        }
+        if negative {
+            mantissa = -mantissa;
+        }
+
+        let scale = i32::try_from(fractional.len())
+            .ok()?
+            .checked_sub(exponent)?;
+        if scale >= 0 {
+            Some(Self {
+                mantissa,
+                scale: scale as u32,
+            })
+        } else {
+            Some(Self {
+                mantissa: mantissa.checked_mul(10i128.checked_pow(scale.unsigned_abs())?)?,
+                scale: 0,
+            })
+        }
+    }
+
+    fn rescale(self, scale: u32) -> Option<Self> {
+        Some(Self {
+            mantissa: self
+                .mantissa
+                .checked_mul(10i128.checked_pow(scale.checked_sub(self.scale)?)?)?,
+            scale,
+        })
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        let scale = self.scale.max(other.scale);
+        Some(Self {
+            mantissa: self
+                .rescale(scale)?
+                .mantissa
+                .checked_add(other.rescale(scale)?.mantissa)?,
+            scale,
+        })
+    }
+
+    fn compare(self, other: Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Some(value), Some(other)) => value.mantissa.cmp(&other.mantissa),
+            // one value is too large to rescale, and so is larger in magnitude than the other
+            _ => self.to_f64().total_cmp(&other.to_f64()),
+        }
+    }
+
+    fn to_f64(self) -> f64 {
+        // parsing the decimal string rounds once, rather than once per digit
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let digits = format!("{digits:0>width$}", width = scale + 1);
+        let (integral, fractional) = digits.split_at(digits.len() - scale);
+        if self.mantissa < 0 {
+            f.write_str("-")?;
+        }
+        f.write_str(integral)?;
+        if !fractional.is_empty() {
+            write!(f, ".{fractional}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{emulated_aggregate_functions, EmulatedFunction};
+    use crate::{coercion::Representation, fixtures};
+
+    const REPRESENTATIONS: [Representation; 10] = [
+        Representation::Boolean,
+        Representation::Integer,
+        Representation::BigInteger,
+        Representation::Float,
+        Representation::Decimal,
+        Representation::String,
+        Representation::Date,
+        Representation::DateTime,
+        Representation::Uuid,
+        Representation::Json,
+    ];
+
+    fn compute(function: EmulatedFunction, representation: Representation, values: Value) -> Value {
+        function.compute(values.as_array().unwrap(), representation)
+    }
+
+    #[test]
+    fn sums_integers_exactly() {
+        let sum = |values| compute(EmulatedFunction::Sum, Representation::Integer, values);
+        assert_eq!(sum(json!([1, 2, 3])), json!(6));
+        assert_eq!(sum(json!(["1", 2, null])), json!(3));
+        assert_eq!(
+            compute(
+                EmulatedFunction::Sum,
+                Representation::BigInteger,
+                json!([i64::MAX, 1])
+            ),
+            json!("9223372036854775808")
+        );
+    }
+
+    #[test]
+    fn sums_decimals_exactly() {
+        let sum = |values| compute(EmulatedFunction::Sum, Representation::Decimal, values);
+        assert_eq!(sum(json!(["0.1", "0.2"])), json!("0.3"));
+        assert_eq!(sum(json!(["1.50", "-2.25", 3])), json!("2.25"));
+        assert_eq!(
+            sum(json!(["12345678901234567890.12345", "0.00001"])),
+            json!("12345678901234567890.12346")
+        );
+        assert_eq!(sum(json!(["-0.5", "0.25"])), json!("-0.25"));
+    }
+
+    #[test]
+    fn sums_floats_as_floats() {
+        assert_eq!(
+            compute(
+                EmulatedFunction::Sum,
+                Representation::Float,
+                json!([1.5, 2])
+            ),
+            json!(3.5)
+        );
+    }
+
+    #[test]
+    fn averages() {
+        let avg = |values| compute(EmulatedFunction::Avg, Representation::Integer, values);
+        assert_eq!(avg(json!([1, 2, 3, 4])), json!(2.5));
+        assert_eq!(avg(json!([1, null, 3])), json!(2.0));
+    }
+
+    #[test]
+    fn averages_decimals_from_their_exact_sum() {
+        // summing these as floats gives 0.30000000000000004, and an average of 0.15000000000000002
+        assert_eq!(
+            compute(
+                EmulatedFunction::Avg,
+                Representation::Decimal,
+                json!(["0.1", "0.2"])
+            ),
+            json!(0.15)
+        );
+        // 2^53 + 1 is not representable as a float
+        assert_eq!(
+            compute(
+                EmulatedFunction::Avg,
+                Representation::BigInteger,
+                json!(["9007199254740993", "1"])
+            ),
+            json!(4503599627370497.0)
+        );
+    }
+
+    #[test]
+    fn variance_and_standard_deviation() {
+        let values = json!([2, 4, 4, 4, 5, 5, 7, 9]);
+        let compute = |function| compute(function, Representation::Integer, values.clone());
+        assert_eq!(compute(EmulatedFunction::VarPop), json!(4.0));
+        assert_eq!(compute(EmulatedFunction::StddevPop), json!(2.0));
+        assert_eq!(compute(EmulatedFunction::VarSamp), json!(32.0 / 7.0));
+        assert_eq!(
+            compute(EmulatedFunction::StddevSamp),
+            json!((32.0_f64 / 7.0).sqrt())
+        );
+    }
+
+    #[test]
+    fn sample_statistics_of_a_single_value_are_null() {
+        let compute = |function| compute(function, Representation::Float, json!([1]));
+        assert_eq!(compute(EmulatedFunction::VarSamp), json!(null));
+        assert_eq!(compute(EmulatedFunction::StddevSamp), json!(null));
+        assert_eq!(compute(EmulatedFunction::VarPop), json!(0.0));
+    }
+
+    #[test]
+    fn min_and_max_compare_numeric_columns_numerically() {
+        assert_eq!(
+            compute(
+                EmulatedFunction::Min,
+                Representation::Integer,
+                json!([3, 10, 2])
+            ),
+            json!(2)
+        );
+        assert_eq!(
+            compute(
+                EmulatedFunction::Max,
+                Representation::BigInteger,
+                json!(["9", "10"])
+            ),
+            json!("10")
+        );
+        assert_eq!(
+            compute(
+                EmulatedFunction::Max,
+                Representation::Decimal,
+                json!(["9.5", "10.25", "-11"])
+            ),
+            json!("10.25")
+        );
+        assert_eq!(
+            compute(
+                EmulatedFunction::Min,
+                Representation::Float,
+                json!([1.5, -2.5, 0])
+            ),
+            json!(-2.5)
+        );
+    }
+
+    #[test]
+    fn min_and_max_compare_string_columns_as_strings() {
+        assert_eq!(
+            compute(
+                EmulatedFunction::Max,
+                Representation::String,
+                json!(["9", "10"])
+            ),
+            json!("9")
+        );
+        assert_eq!(
+            compute(
+                EmulatedFunction::Min,
+                Representation::String,
+                json!(["10", "9", "a"])
+            ),
+            json!("10")
+        );
+
+        let dates = json!(["2023-02-01", "2023-01-15", "2023-03-01"]);
+        assert_eq!(
+            compute(EmulatedFunction::Min, Representation::Date, dates.clone()),
+            json!("2023-01-15")
+        );
+        assert_eq!(
+            compute(EmulatedFunction::Max, Representation::Date, dates),
+            json!("2023-03-01")
+        );
+    }
+
+    #[test]
+    fn min_and_max_ignore_values_not_in_the_column_representation() {
+        assert_eq!(
+            compute(
+                EmulatedFunction::Max,
+                Representation::Integer,
+                json!(["abc", 2, 1])
+            ),
+            json!(2)
+        );
+        assert_eq!(
+            compute(
+                EmulatedFunction::Max,
+                Representation::String,
+                json!([10, "b", "a"])
+            ),
+            json!("b")
+        );
+    }
+
+    #[test]
+    fn longest_and_shortest_count_characters() {
+        let compute = |function, values| compute(function, Representation::String, values);
+        let values = json!(["ab", "ééé", "a", "cd"]);
+        assert_eq!(
+            compute(EmulatedFunction::Longest, values.clone()),
+            json!("ééé")
+        );
+        assert_eq!(compute(EmulatedFunction::Shortest, values), json!("a"));
+        // ties keep the first value
+        assert_eq!(
+            compute(EmulatedFunction::Longest, json!(["ab", "cd"])),
+            json!("ab")
+        );
+    }
+
+    #[test]
+    fn counts_distinct_non_null_values() {
+        let count = |values| {
+            compute(
+                EmulatedFunction::CountDistinct,
+                Representation::Json,
+                values,
+            )
+        };
+        assert_eq!(count(json!([1, 1, 2, null, "a", "a"])), json!(3));
+        assert_eq!(count(json!([])), json!(0));
+    }
+
+    #[test]
+    fn aggregates_of_no_values_are_null() {
+        for (function, representation) in [
+            (EmulatedFunction::Sum, Representation::Integer),
+            (EmulatedFunction::Sum, Representation::Decimal),
+            (EmulatedFunction::Avg, Representation::Decimal),
+            (EmulatedFunction::Avg, Representation::Float),
+            (EmulatedFunction::Min, Representation::Integer),
+            (EmulatedFunction::Max, Representation::String),
+            (EmulatedFunction::StddevPop, Representation::Float),
+            (EmulatedFunction::VarPop, Representation::Float),
+            (EmulatedFunction::Longest, Representation::String),
+            (EmulatedFunction::Shortest, Representation::String),
+        ] {
+            assert_eq!(
+                compute(function, representation, json!([null, null])),
+                json!(null),
+                "{function:?}"
+            );
+        }
+    }
+
+    fn applicable_representations(function: EmulatedFunction) -> Vec<Representation> {
+        REPRESENTATIONS
+            .into_iter()
+            .filter(|representation| function.applies_to(*representation))
+            .collect()
+    }
+
+    #[test]
+    fn statistical_functions_apply_to_numbers() {
+        for function in [
+            EmulatedFunction::Sum,
+            EmulatedFunction::Avg,
+            EmulatedFunction::StddevPop,
+            EmulatedFunction::StddevSamp,
+            EmulatedFunction::VarPop,
+            EmulatedFunction::VarSamp,
+        ] {
+            assert_eq!(
+                applicable_representations(function),
+                vec![
+                    Representation::Integer,
+                    Representation::BigInteger,
+                    Representation::Float,
+                    Representation::Decimal,
+                ],
+                "{function:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn min_and_max_apply_to_ordered_representations() {
+        for function in [EmulatedFunction::Min, EmulatedFunction::Max] {
+            assert_eq!(
+                applicable_representations(function),
+                vec![
+                    Representation::Integer,
+                    Representation::BigInteger,
+                    Representation::Float,
+                    Representation::Decimal,
+                    Representation::String,
+                    Representation::Date,
+                    Representation::DateTime,
+                ],
+                "{function:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn longest_and_shortest_apply_to_strings() {
+        for function in [EmulatedFunction::Longest, EmulatedFunction::Shortest] {
+            assert_eq!(
+                applicable_representations(function),
+                vec![Representation::String],
+                "{function:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn distinct_counts_apply_to_everything_but_json() {
+        let representations = applicable_representations(EmulatedFunction::CountDistinct);
+        assert_eq!(representations.len(), REPRESENTATIONS.len() - 1);
+        assert!(!representations.contains(&Representation::Json));
+    }
+
+    #[test]
+    fn advertises_functions_the_target_lacks_for_the_scalar_type() {
+        let schema = fixtures::schema();
+        let advertised = |scalar_type| {
+            emulated_aggregate_functions(&schema, scalar_type)
+                .into_iter()
+                .map(|(name, result_type)| format!("{name}: {result_type}"))
+                .collect::<Vec<_>>()
+        };
+
+        // Int32 has its own min, max and sum
+        assert_eq!(
+            advertised("Int32"),
+            vec![
+                "avg: Float64",
+                "stddev: Float64",
+                "stddev_pop: Float64",
+                "stddev_samp: Float64",
+                "variance: Float64",
+                "var_pop: Float64",
+                "var_samp: Float64",
+            ]
+        );
+        assert_eq!(
+            advertised("String"),
+            vec![
+                "min: String",
+                "max: String",
+                "longest: String",
+                "shortest: String",
+            ]
+        );
+        assert!(advertised("Bool").is_empty());
+        assert!(advertised("JSON").is_empty());
+        // types without a known representation
+        assert!(advertised("Geometry").is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use url::{Host, Url};
+
+use crate::{api::error_response::ErrorResponseType, error::ServerError};
+
+/// The targets the proxy may send requests to, so callers cannot use it to reach arbitrary URLs
+#[derive(Debug, Clone)]
+pub struct TargetAllowlist {
+    pub schemes: Vec<String>,
+    /// Exact host names, or patterns like `*.example.com` matching any subdomain
+    pub hosts: Vec<String>,
+    pub networks: Vec<Network>,
+}
+
+/// An IP network in CIDR notation, such as `10.0.0.0/8`
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match s.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid network address {address}"))?;
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse()
+                .ok()
+                .filter(|prefix_length| *prefix_length <= max_prefix_length)
+                .ok_or_else(|| format!("invalid prefix length {prefix_length}"))?,
+            None => max_prefix_length,
+        };
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+}
+
+impl Network {
+    fn contains(&self, address: IpAddr) -> bool {
+        let (network, address, bits) = match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (u32::from(network) as u128, u32::from(address) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address), 128)
+            }
+            (IpAddr::V6(network), IpAddr::V4(address)) => (
+                u128::from(network),
+                u128::from(address.to_ipv6_mapped()),
+                128,
+            ),
+            (IpAddr::V4(_), IpAddr::V6(address)) => match address.to_ipv4_mapped() {
+                Some(address) => return self.contains(IpAddr::V4(address)),
+                None => return false,
+            },
+        };
+        if self.prefix_length == 0 {
+            return true;
+        }
+        let shift = bits - self.prefix_length as u32;
+        network >> shift == address >> shift
+    }
+}
+
+impl TargetAllowlist {
+    /// Whether any hosts or networks are restricted. Only schemes are checked otherwise.
+    fn restricts_hosts(&self) -> bool {
+        !self.hosts.is_empty() || !self.networks.is_empty()
+    }
+
+    /// Refuse target URLs not allowed by the allowlist.
+    /// Host names not matching a host pattern are resolved, and allowed only if every address is in an allowed network.
+    pub async fn check(&self, target_url: &str) -> Result<(), ServerError> {
+        let url = Url::parse(target_url)
+            .map_err(|err| not_allowed(target_url, format!("Unable to parse target URL: {err}")))?;
+
+        if !self
+            .schemes
+            .iter()
+            .any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(not_allowed(
+                target_url,
+                format!("Target URL scheme {} is not allowed", url.scheme()),
+            ));
+        }
+
+        if !self.restricts_hosts() {
+            return Ok(());
+        }
+
+        let host = match url.host() {
+            Some(host) => host,
+            None => {
+                return Err(not_allowed(
+                    target_url,
+                    "Target URL has no host".to_string(),
+                ))
+            }
+        };
+
+        let addresses: Vec<IpAddr> = match &host {
+            Host::Ipv4(address) => vec![IpAddr::V4(*address)],
+            Host::Ipv6(address) => vec![IpAddr::V6(*address)],
+            Host::Domain(domain) => {
+                if self
+                    .hosts
+                    .iter()
+                    .any(|pattern| host_matches(pattern, domain))
+                {
+                    return Ok(());
+                }
+                if self.networks.is_empty() {
+                    vec![]
+                } else {
+                    let port = url.port_or_known_default().unwrap_or(80);
+                    tokio::net::lookup_host((*domain, port))
+                        .await
+                        .map(|addresses| addresses.map(|address| address.ip()).collect())
+                        .unwrap_or_default()
+                }
+            }
+        };
+
+        if !addresses.is_empty()
+            && addresses.iter().all(|address| {
+                self.networks
+                    .iter()
+                    .any(|network| network.contains(*address))
+            })
+        {
+            Ok(())
+        } else {
+            Err(not_allowed(
+                target_url,
+                format!("Target host {host} is not allowed"),
+            ))
+        }
+    }
+}
+
+/// Resolves target host names for the upstream client, enforcing the allowed networks on the addresses it connects to.
+/// Checking the target URL alone is not enough, as a host name could resolve to another address by the time the request is sent.
+pub struct AllowlistResolver(pub Arc<TargetAllowlist>);
+
+impl Resolve for AllowlistResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowlist = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses: Vec<_> = tokio::net::lookup_host((host, 0)).await?.collect();
+
+            let allowed = !allowlist.restricts_hosts()
+                || allowlist
+                    .hosts
+                    .iter()
+                    .any(|pattern| host_matches(pattern, host))
+                || addresses.iter().all(|address| {
+                    allowlist
+                        .networks
+                        .iter()
+                        .any(|network| network.contains(address.ip()))
+                });
+
+            if allowed {
+                Ok(Box::new(addresses.into_iter()) as Addrs)
+            } else {
+                Err(format!("Target host {host} resolved to an address that is not allowed").into())
+            }
+        })
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .to_ascii_lowercase()
+            .strip_suffix(&domain.to_ascii_lowercase())
+            .map_or(false, |subdomain| {
+                subdomain.ends_with('.') && subdomain.len() > 1
+            }),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn not_allowed(target_url: &str, message: String) -> ServerError {
+    ServerError::UncaughtError {
+        details: Some(serde_json::json!({ "target_url": target_url })),
+        message,
+        error_type: ErrorResponseType::UncaughtError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{host_matches, Network};
+
+    fn contains(network: &str, address: &str) -> bool {
+        let network: Network = network.parse().unwrap();
+        network.contains(address.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn contains_addresses_within_the_prefix() {
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+    }
+
+    #[test]
+    fn networks_without_a_prefix_are_single_addresses() {
+        assert!(contains("10.0.0.1", "10.0.0.1"));
+        assert!(!contains("10.0.0.1", "10.0.0.2"));
+        assert!(contains("::1", "::1"));
+    }
+
+    #[test]
+    fn zero_prefix_contains_every_address() {
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("::/0", "203.0.113.7"));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_ipv6_addresses() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/104", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "2001:db8::1"));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("::/129".parse::<Network>().is_err());
+        assert!("example.com/8".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn wildcard_hosts_match_subdomains_only() {
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(host_matches("*.example.com", "API.Example.com."));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", ".example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn exact_hosts_match_case_insensitively() {
+        assert!(host_matches("example.com", "Example.COM"));
+        assert!(host_matches("example.com", "example.com."));
+        assert!(!host_matches("example.com", "api.example.com"));
+    }
+}
@@ -1,3 +1,5 @@
+pub mod scalar_type;
+
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_json::{Number, Value};
@@ -31,7 +33,7 @@ pub struct QueryRequest {
 pub type ForEach = IndexMap<String, ForEachValue>;
 
 #[skip_serializing_none]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(bound = "")]
 pub struct Query {
     /// Aggregate fields of the query
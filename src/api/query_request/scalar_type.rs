@@ -1,5 +1,3 @@
-use std::fmt::{Display, Formatter};
-
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -21,6 +19,11 @@ pub enum ScalarType {
     Int256,
     Float32,
     Float64,
+    Decimal,
+    Decimal32,
+    Decimal64,
+    Decimal128,
+    Decimal256,
     Date,
     Date32,
     DateTime,
@@ -32,6 +35,13 @@ pub enum ScalarType {
     IPv4,
     IPv6,
 }
+
+impl ScalarType {
+    /// Parse a scalar type name as used by v2 and v3, returning None for types we don't know about
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_owned())).ok()
+    }
+}
 // impl Display for ClickhouseScalarType {
 //     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
 //         match self {
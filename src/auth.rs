@@ -0,0 +1,249 @@
+use std::{path::Path, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderName, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, DecodingKey, Validation};
+
+use crate::{
+    api::error_response::{ErrorResponse, ErrorResponseType},
+    config::SOURCE_HEADER,
+};
+
+/// How callers of the proxy authenticate. Requests must pass every configured check.
+pub struct InboundAuth {
+    pub shared_secret: Option<SharedSecret>,
+    pub jwt: Option<JwtAuth>,
+}
+
+/// A secret callers send in a request header
+pub struct SharedSecret {
+    pub header: HeaderName,
+    pub secret: String,
+}
+
+/// Bearer tokens signed by one of a set of keys, each with the algorithm it declares
+pub struct JwtAuth {
+    keys: JwkSet,
+    audience: Option<String>,
+    issuer: Option<String>,
+}
+
+impl JwtAuth {
+    pub fn load(
+        jwks_file: &Path,
+        audience: Option<String>,
+        issuer: Option<String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = serde_json::from_str(&std::fs::read_to_string(jwks_file)?)?;
+        Ok(Self::new(keys, audience, issuer)?)
+    }
+
+    /// Keys must declare their algorithm, as the algorithm in the header of a token cannot be trusted
+    fn new(keys: JwkSet, audience: Option<String>, issuer: Option<String>) -> Result<Self, String> {
+        if let Some(key) = keys.keys.iter().find(|key| key.common.algorithm.is_none()) {
+            return Err(format!(
+                "Key {} in the JWKS file does not declare its algorithm with alg",
+                key.common.key_id.as_deref().unwrap_or("without a kid")
+            ));
+        }
+        Ok(Self {
+            keys,
+            audience,
+            issuer,
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<(), String> {
+        let token_header = decode_header(token).map_err(|err| err.to_string())?;
+
+        let key = match &token_header.kid {
+            Some(kid) => self.keys.find(kid),
+            // without a key id, the key is only unambiguous if there is a single one
+            None if self.keys.keys.len() == 1 => self.keys.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| "No matching key for token".to_string())?;
+        let algorithm = key
+            .common
+            .algorithm
+            .ok_or_else(|| "Key does not declare its algorithm".to_string())?;
+        if token_header.alg != algorithm {
+            return Err(format!(
+                "Token algorithm {:?} does not match the key algorithm {:?}",
+                token_header.alg, algorithm
+            ));
+        }
+        let key = DecodingKey::from_jwk(key).map_err(|err| err.to_string())?;
+
+        let mut validation = Validation::new(algorithm);
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        decode::<serde_json::Value>(token, &key, &validation)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl InboundAuth {
+    pub fn is_enabled(&self) -> bool {
+        self.shared_secret.is_some() || self.jwt.is_some()
+    }
+
+    fn check<B>(&self, request: &Request<B>) -> Result<(), String> {
+        if let Some(shared_secret) = &self.shared_secret {
+            let secret = request
+                .headers()
+                .get(&shared_secret.header)
+                .ok_or_else(|| format!("Missing {} header", shared_secret.header))?;
+            if !constant_time_eq(secret.as_bytes(), shared_secret.secret.as_bytes()) {
+                return Err(format!("Invalid {} header", shared_secret.header));
+            }
+        }
+
+        if let Some(jwt) = &self.jwt {
+            let token = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|authorization| authorization.to_str().ok())
+                .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                .ok_or_else(|| "Missing bearer token".to_string())?;
+            jwt.verify(token.trim())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Middleware refusing requests that fail inbound authentication
+pub async fn authenticate<B>(
+    State(auth): State<Arc<InboundAuth>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match auth.check(&request) {
+        Ok(()) => next.run(request).await,
+        Err(message) => unauthorized(message),
+    }
+}
+
+/// Middleware for routes open to all callers, such as health checks, which only need authentication when they act on a source.
+/// Checking a source calls its target with the source's credentials, which must not be possible around inbound authentication.
+pub async fn authenticate_source_requests<B>(
+    State(auth): State<Arc<InboundAuth>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !request.headers().contains_key(&SOURCE_HEADER) {
+        return next.run(request).await;
+    }
+
+    match auth.check(&request) {
+        Ok(()) => next.run(request).await,
+        Err(message) => unauthorized(message),
+    }
+}
+
+fn unauthorized(message: String) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        axum::Json(ErrorResponse {
+            details: None,
+            message,
+            error_type: ErrorResponseType::UncaughtError,
+        }),
+    )
+        .into_response()
+}
+
+/// Compare secrets in time independent of where they differ, so they cannot be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::JwtAuth;
+
+    const SECRET: &str = "AAAABBBBCCCCDDDDEEEEFFFFGGGGHHHH";
+
+    fn auth(key: serde_json::Value) -> Result<JwtAuth, String> {
+        let keys = serde_json::from_value(json!({ "keys": [key] })).unwrap();
+        JwtAuth::new(keys, Some("proxy".to_owned()), None)
+    }
+
+    fn hs256_key() -> serde_json::Value {
+        json!({ "kty": "oct", "kid": "test", "alg": "HS256", "k": SECRET })
+    }
+
+    fn token(algorithm: Algorithm, audience: &str, expires_in: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut header = Header::new(algorithm);
+        header.kid = Some("test".to_owned());
+        let claims = json!({ "aud": audience, "exp": now + expires_in });
+        let key = EncodingKey::from_base64_secret(SECRET).unwrap();
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_tokens() {
+        let auth = auth(hs256_key()).unwrap();
+        assert_eq!(auth.verify(&token(Algorithm::HS256, "proxy", 3600)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let auth = auth(hs256_key()).unwrap();
+        assert!(auth
+            .verify(&token(Algorithm::HS256, "proxy", -3600))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_tokens_for_other_audiences() {
+        let auth = auth(hs256_key()).unwrap();
+        assert!(auth
+            .verify(&token(Algorithm::HS256, "another", 3600))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_tokens_with_another_algorithm_than_their_key() {
+        let auth = auth(hs256_key()).unwrap();
+        let error = auth
+            .verify(&token(Algorithm::HS384, "proxy", 3600))
+            .unwrap_err();
+        assert!(
+            error.contains("does not match the key algorithm"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn requires_keys_to_declare_their_algorithm() {
+        let error = auth(json!({ "kty": "oct", "kid": "test", "k": SECRET }))
+            .err()
+            .unwrap();
+        assert!(error.contains("Key test"), "{error}");
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap},
+};
+use indexmap::IndexMap;
+use ndc_client::models;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 digest identifying cached responses. A collision would serve one caller's response to another,
+/// so keys are digests rather than 64 bit hashes.
+pub type CacheKey = [u8; 32];
+
+/// In memory LRU cache of query responses from targets, shared by all sources.
+/// Entries are the raw response bodies, keyed by a digest of the target URL, the credentials sent to it, and the normalised v3 request.
+pub struct QueryCache {
+    /// The maximum total size in bytes of cached responses
+    max_size: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct CacheState {
+    /// Entries in order of use, least recently used first
+    entries: IndexMap<CacheKey, CacheEntry>,
+    size: usize,
+}
+
+struct CacheEntry {
+    body: Bytes,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+/// The `Cache-Control` directives of an incoming request that affect the query cache
+#[derive(Debug, Clone, Default)]
+pub struct CacheControl {
+    /// Do not serve a cached response, but the fresh response may be cached
+    pub no_cache: bool,
+    /// Neither serve nor cache a response
+    pub no_store: bool,
+    /// Only serve cached responses younger than this
+    pub max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_lowercase());
+
+        for directive in directives {
+            match directive.split_once('=') {
+                Some(("max-age", seconds)) => {
+                    cache_control.max_age = seconds
+                        .trim_matches('"')
+                        .parse()
+                        .ok()
+                        .map(Duration::from_secs)
+                }
+                None if directive == "no-cache" => cache_control.no_cache = true,
+                None if directive == "no-store" => cache_control.no_store = true,
+                _ => {}
+            }
+        }
+
+        cache_control
+    }
+}
+
+impl QueryCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            state: Mutex::new(CacheState {
+                entries: IndexMap::new(),
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// The cache key for a request to a target. Object keys are sorted first, so the key does not depend on map iteration order.
+    pub fn key(
+        url: &str,
+        credentials: &CacheKey,
+        request: &impl Serialize,
+    ) -> Result<CacheKey, serde_json::Error> {
+        let mut hasher = Sha256::new();
+        hash_bytes(url.as_bytes(), &mut hasher);
+        hasher.update(credentials);
+        hash_value(&serde_json::to_value(request)?, &mut hasher);
+        Ok(hasher.finalize().into())
+    }
+
+    pub fn get(&self, key: CacheKey, cache_control: &CacheControl) -> Option<Bytes> {
+        if cache_control.no_cache || cache_control.no_store {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let lookup = state.entries.get(&key).map(|entry| {
+            let expired = entry.expires_at <= now;
+            let too_old = cache_control.max_age.map_or(false, |max_age| {
+                now.duration_since(entry.stored_at) > max_age
+            });
+            (expired, (!expired && !too_old).then(|| entry.body.clone()))
+        });
+        if let Some((true, _)) = lookup {
+            state.remove(key);
+        }
+        let body = lookup.and_then(|(_, body)| body);
+
+        if body.is_some() {
+            // move the entry to the back, as the most recently used
+            if let Some(entry) = state.entries.shift_remove(&key) {
+                state.entries.insert(key, entry);
+            }
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        body
+    }
+
+    pub fn insert(&self, key: CacheKey, body: Bytes, ttl: Duration, cache_control: &CacheControl) {
+        if cache_control.no_store || body.len() > self.max_size {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+
+        while state.size + body.len() > self.max_size {
+            match state.entries.shift_remove_index(0) {
+                Some((_, evicted)) => {
+                    state.size -= evicted.body.len();
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+
+        let now = Instant::now();
+        state.size += body.len();
+        state.entries.insert(
+            key,
+            CacheEntry {
+                body,
+                stored_at: now,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    /// Cache metrics in the Prometheus text exposition format
+    pub fn metrics(&self) -> String {
+        let (entries, size) = {
+            let state = self.state.lock().unwrap();
+            (state.entries.len(), state.size)
+        };
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let hit_rate = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+
+        [
+            (
+                "query_cache_hits_total",
+                "counter",
+                "Queries answered from the cache",
+                hits.to_string(),
+            ),
+            (
+                "query_cache_misses_total",
+                "counter",
+                "Queries not found in the cache",
+                misses.to_string(),
+            ),
+            (
+                "query_cache_evictions_total",
+                "counter",
+                "Cache entries evicted to make room for new entries",
+                self.evictions.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "query_cache_hit_rate",
+                "gauge",
+                "Fraction of cache lookups that were hits",
+                hit_rate.to_string(),
+            ),
+            (
+                "query_cache_entries",
+                "gauge",
+                "Number of cached responses",
+                entries.to_string(),
+            ),
+            (
+                "query_cache_size_bytes",
+                "gauge",
+                "Total size of cached responses",
+                size.to_string(),
+            ),
+        ]
+        .iter()
+        .map(|(name, metric_type, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} {metric_type}\n{name} {value}\n")
+        })
+        .collect()
+    }
+}
+
+/// The number of targets whose schema is cached. The least recently used is evicted to make room for another.
+const SCHEMA_CACHE_ENTRIES: usize = 256;
+
+/// The capabilities and schema of a target, which every query needs to be translated
+pub struct TargetSchema {
+    pub capabilities: models::CapabilitiesResponse,
+    pub schema: models::SchemaResponse,
+}
+
+/// In memory LRU cache of target schemas, so queries do not fetch them from the target every time.
+/// Entries are keyed by the target URL and the credentials sent to it, as the schema may depend on the caller's role.
+pub struct SchemaCache {
+    ttl: Duration,
+    /// Entries in order of use, least recently used first
+    entries: Mutex<IndexMap<(String, CacheKey), (Instant, Arc<TargetSchema>)>>,
+}
+
+impl SchemaCache {
+    /// A cache keeping schemas for the given time. A zero duration disables caching.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(IndexMap::new()),
+        }
+    }
+
+    pub fn get(&self, url: &str, credentials: &CacheKey) -> Option<Arc<TargetSchema>> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (url.to_owned(), *credentials);
+
+        // removing the entry and inserting it again moves it to the back, as the most recently used
+        let (expires_at, schema) = entries.shift_remove(&key)?;
+        if expires_at <= Instant::now() {
+            return None;
+        }
+        entries.insert(key, (expires_at, schema.clone()));
+
+        Some(schema)
+    }
+
+    pub fn insert(&self, url: &str, credentials: &CacheKey, schema: Arc<TargetSchema>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let key = (url.to_owned(), *credentials);
+        entries.shift_remove(&key);
+
+        while entries.len() >= SCHEMA_CACHE_ENTRIES {
+            entries.shift_remove_index(0);
+        }
+
+        entries.insert(key, (Instant::now() + self.ttl, schema));
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: CacheKey) {
+        if let Some(entry) = self.entries.shift_remove(&key) {
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+/// Feed a JSON value to the hasher. Strings and collections are prefixed with their length, so different values never produce the same input.
+fn hash_value(value: &Value, hasher: &mut Sha256) {
+    match value {
+        Value::Null => hasher.update([0]),
+        Value::Bool(boolean) => hasher.update([1, *boolean as u8]),
+        Value::Number(number) => {
+            hasher.update([2]);
+            hash_bytes(number.to_string().as_bytes(), hasher);
+        }
+        Value::String(string) => {
+            hasher.update([3]);
+            hash_bytes(string.as_bytes(), hasher);
+        }
+        Value::Array(values) => {
+            hasher.update([4]);
+            hasher.update((values.len() as u64).to_be_bytes());
+            for value in values {
+                hash_value(value, hasher);
+            }
+        }
+        Value::Object(object) => {
+            hasher.update([5]);
+            hasher.update((object.len() as u64).to_be_bytes());
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, value) in entries {
+                hash_bytes(key.as_bytes(), hasher);
+                hash_value(value, hasher);
+            }
+        }
+    }
+}
+
+pub fn hash_bytes(bytes: &[u8], hasher: &mut Sha256) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::Bytes,
+        http::{header, HeaderMap, HeaderValue},
+    };
+    use serde_json::json;
+
+    use super::{CacheControl, CacheKey, QueryCache};
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn key(name: &str) -> CacheKey {
+        QueryCache::key(
+            "http://localhost:8100/query",
+            &[0; 32],
+            &json!({ "name": name }),
+        )
+        .unwrap()
+    }
+
+    fn insert(cache: &QueryCache, name: &'static str, ttl: Duration) {
+        cache.insert(key(name), Bytes::from(name), ttl, &CacheControl::default());
+    }
+
+    fn get(cache: &QueryCache, name: &str) -> Option<Bytes> {
+        cache.get(key(name), &CacheControl::default())
+    }
+
+    fn cache_control(value: &'static str) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+        CacheControl::from_headers(&headers)
+    }
+
+    #[test]
+    fn serves_entries_until_they_expire() {
+        let cache = QueryCache::new(1024);
+        insert(&cache, "fresh", TTL);
+        insert(&cache, "expired", Duration::ZERO);
+
+        assert_eq!(get(&cache, "fresh"), Some(Bytes::from("fresh")));
+        assert_eq!(get(&cache, "expired"), None);
+        assert!(cache.metrics().contains("query_cache_entries 1\n"));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entries() {
+        let cache = QueryCache::new(8);
+        insert(&cache, "aaaa", TTL);
+        insert(&cache, "bbbb", TTL);
+        // reading the first entry makes the second the least recently used
+        assert!(get(&cache, "aaaa").is_some());
+        insert(&cache, "cccc", TTL);
+
+        assert!(get(&cache, "aaaa").is_some());
+        assert!(get(&cache, "bbbb").is_none());
+        assert!(get(&cache, "cccc").is_some());
+        assert!(cache.metrics().contains("query_cache_evictions_total 1\n"));
+    }
+
+    #[test]
+    fn does_not_cache_entries_larger_than_the_cache() {
+        let cache = QueryCache::new(4);
+        insert(&cache, "too large", TTL);
+        assert!(get(&cache, "too large").is_none());
+        assert!(cache.metrics().contains("query_cache_size_bytes 0\n"));
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = QueryCache::new(1024);
+        insert(&cache, "cached", TTL);
+        get(&cache, "cached");
+        get(&cache, "missing");
+
+        let metrics = cache.metrics();
+        assert!(metrics.contains("query_cache_hits_total 1\n"));
+        assert!(metrics.contains("query_cache_misses_total 1\n"));
+        assert!(metrics.contains("query_cache_hit_rate 0.5\n"));
+    }
+
+    #[test]
+    fn parses_cache_control_directives() {
+        let parsed = cache_control("No-Cache, max-age=\"30\"");
+        assert!(parsed.no_cache);
+        assert!(!parsed.no_store);
+        assert_eq!(parsed.max_age, Some(Duration::from_secs(30)));
+
+        let parsed = cache_control("no-store, private");
+        assert!(parsed.no_store);
+        assert_eq!(parsed.max_age, None);
+    }
+
+    #[test]
+    fn no_cache_refreshes_entries_and_no_store_bypasses_the_cache() {
+        let cache = QueryCache::new(1024);
+        let no_cache = cache_control("no-cache");
+        let no_store = cache_control("no-store");
+
+        cache.insert(key("refreshed"), Bytes::from("refreshed"), TTL, &no_cache);
+        assert!(cache.get(key("refreshed"), &no_cache).is_none());
+        assert!(get(&cache, "refreshed").is_some());
+
+        cache.insert(key("bypassed"), Bytes::from("bypassed"), TTL, &no_store);
+        assert!(get(&cache, "bypassed").is_none());
+    }
+
+    #[test]
+    fn max_age_refuses_older_entries() {
+        let cache = QueryCache::new(1024);
+        insert(&cache, "cached", TTL);
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(cache
+            .get(key("cached"), &cache_control("max-age=0"))
+            .is_none());
+        assert!(cache
+            .get(key("cached"), &cache_control("max-age=60"))
+            .is_some());
+    }
+
+    #[test]
+    fn keys_do_not_depend_on_object_key_order_but_on_credentials() {
+        let url = "http://localhost:8100/query";
+        let request = json!({ "table": "Album", "limit": 10 });
+        let reordered = json!({ "limit": 10, "table": "Album" });
+
+        assert_eq!(
+            QueryCache::key(url, &[0; 32], &request).unwrap(),
+            QueryCache::key(url, &[0; 32], &reordered).unwrap()
+        );
+        assert_ne!(
+            QueryCache::key(url, &[0; 32], &request).unwrap(),
+            QueryCache::key(url, &[1; 32], &request).unwrap()
+        );
+    }
+}
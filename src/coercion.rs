@@ -0,0 +1,240 @@
+use serde_json::{Number, Value};
+
+use crate::api::{capabilities_response::GraphQlType, query_request::scalar_type::ScalarType};
+
+/// How values of a scalar type are represented in JSON.
+/// HGE expects values in the form matching the GraphQL type we advertise for the scalar type,
+/// while v3 connectors expect values in their native form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Boolean,
+    /// Integers that fit in a GraphQL Int, represented as JSON numbers on both sides
+    Integer,
+    /// Integers that may not fit in a GraphQL Int or a JSON number, represented as strings to HGE
+    BigInteger,
+    Float,
+    /// Arbitrary precision numbers, represented as strings on both sides so no precision is lost
+    Decimal,
+    String,
+    /// Dates, represented as `YYYY-MM-DD` strings
+    Date,
+    /// Timestamps, represented as ISO 8601 strings to HGE
+    DateTime,
+    /// UUIDs, represented as lowercase hyphenated strings
+    Uuid,
+    Json,
+}
+
+impl Representation {
+    pub fn of(scalar_type: &str) -> Option<Self> {
+        use ScalarType::*;
+        Some(match ScalarType::from_name(scalar_type)? {
+            Bool => Self::Boolean,
+            String | FixedString | IPv4 | IPv6 => Self::String,
+            UInt8 | UInt16 | Int8 | Int16 | Int32 => Self::Integer,
+            UInt32 | UInt64 | UInt128 | UInt256 | Int64 | Int128 | Int256 => Self::BigInteger,
+            Float32 | Float64 => Self::Float,
+            Decimal | Decimal32 | Decimal64 | Decimal128 | Decimal256 => Self::Decimal,
+            Date | Date32 => Self::Date,
+            DateTime | DateTime64 => Self::DateTime,
+            Json => Self::Json,
+            Uuid => Self::Uuid,
+        })
+    }
+
+    pub fn graphql_type(&self) -> GraphQlType {
+        match self {
+            Self::Boolean => GraphQlType::Boolean,
+            Self::Integer => GraphQlType::Int,
+            Self::Float => GraphQlType::Float,
+            Self::BigInteger
+            | Self::Decimal
+            | Self::String
+            | Self::Date
+            | Self::DateTime
+            | Self::Uuid
+            | Self::Json => GraphQlType::String,
+        }
+    }
+}
+
+pub fn graphql_type(scalar_type: &str) -> GraphQlType {
+    Representation::of(scalar_type)
+        .map(|representation| representation.graphql_type())
+        .unwrap_or(GraphQlType::String)
+}
+
+/// Coerce a value sent by HGE into the form expected by the v3 connector.
+/// Values of unknown types, or that can't be coerced, are passed through unchanged and left for the connector to reject.
+pub fn to_v3(value: Value, scalar_type: &str) -> Value {
+    let representation = match Representation::of(scalar_type) {
+        Some(representation) => representation,
+        None => return value,
+    };
+
+    match (representation, value) {
+        (Representation::Boolean, Value::String(string)) => match string.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(string),
+        },
+        (Representation::Integer | Representation::BigInteger, Value::String(string)) => {
+            parse_integer(&string).unwrap_or(Value::String(string))
+        }
+        (Representation::Float, Value::String(string)) => string
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or(Value::String(string)),
+        (Representation::Decimal, Value::Number(number)) => Value::String(number.to_string()),
+        (Representation::Uuid, Value::String(string)) => Value::String(string.to_lowercase()),
+        (_, value) => value,
+    }
+}
+
+/// Coerce a value returned by the v3 connector into the form HGE expects for the GraphQL type of the scalar type.
+pub fn to_v2(value: Value, scalar_type: &str) -> Value {
+    let representation = match Representation::of(scalar_type) {
+        Some(representation) => representation,
+        None => return value,
+    };
+
+    match (representation, value) {
+        (Representation::Boolean, Value::Number(number)) => match number.as_u64() {
+            Some(0) => Value::Bool(false),
+            Some(1) => Value::Bool(true),
+            _ => Value::Number(number),
+        },
+        (Representation::Integer, Value::String(string)) => {
+            parse_integer(&string).unwrap_or(Value::String(string))
+        }
+        (Representation::Float, Value::String(string)) => string
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or(Value::String(string)),
+        (Representation::BigInteger | Representation::Decimal, Value::Number(number)) => {
+            Value::String(number.to_string())
+        }
+        (Representation::Date, Value::String(string)) => match string.split_once([' ', 'T']) {
+            Some((date, _)) => Value::String(date.to_owned()),
+            None => Value::String(string),
+        },
+        (Representation::DateTime, Value::String(string)) => {
+            Value::String(string.replacen(' ', "T", 1))
+        }
+        (Representation::Uuid, Value::String(string)) => Value::String(string.to_lowercase()),
+        (_, value) => value,
+    }
+}
+
+fn parse_integer(string: &str) -> Option<Value> {
+    if let Ok(integer) = string.parse::<i64>() {
+        Some(Value::Number(integer.into()))
+    } else if let Ok(integer) = string.parse::<u64>() {
+        Some(Value::Number(integer.into()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{to_v2, to_v3};
+
+    #[test]
+    fn booleans() {
+        assert_eq!(to_v3(json!("true"), "Bool"), json!(true));
+        assert_eq!(to_v3(json!(false), "Bool"), json!(false));
+        assert_eq!(to_v2(json!(1), "Bool"), json!(true));
+        assert_eq!(to_v2(json!(0), "Bool"), json!(false));
+        assert_eq!(to_v2(json!(2), "Bool"), json!(2));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(to_v3(json!("42"), "Int32"), json!(42));
+        assert_eq!(to_v2(json!("42"), "Int32"), json!(42));
+        assert_eq!(to_v2(json!(-7), "Int8"), json!(-7));
+    }
+
+    #[test]
+    fn big_integers_are_strings_to_hge() {
+        assert_eq!(
+            to_v2(json!(18446744073709551615u64), "UInt64"),
+            json!("18446744073709551615")
+        );
+        assert_eq!(
+            to_v3(json!("18446744073709551615"), "UInt64"),
+            json!(18446744073709551615u64)
+        );
+        assert_eq!(
+            to_v3(json!("-9223372036854775808"), "Int64"),
+            json!(i64::MIN)
+        );
+        // too large for a JSON number, so left for the connector
+        assert_eq!(
+            to_v3(json!("340282366920938463463374607431768211455"), "UInt128"),
+            json!("340282366920938463463374607431768211455")
+        );
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(to_v3(json!("1.5"), "Float64"), json!(1.5));
+        assert_eq!(to_v2(json!("1.5"), "Float32"), json!(1.5));
+        assert_eq!(to_v3(json!("NaN"), "Float64"), json!("NaN"));
+    }
+
+    #[test]
+    fn decimals_are_strings_on_both_sides() {
+        assert_eq!(to_v3(json!(1.25), "Decimal64"), json!("1.25"));
+        assert_eq!(to_v2(json!(1.25), "Decimal"), json!("1.25"));
+        assert_eq!(to_v2(json!("1.25"), "Decimal"), json!("1.25"));
+    }
+
+    #[test]
+    fn dates_drop_the_time() {
+        assert_eq!(
+            to_v2(json!("2023-07-01 00:00:00"), "Date"),
+            json!("2023-07-01")
+        );
+        assert_eq!(
+            to_v2(json!("2023-07-01T00:00:00Z"), "Date32"),
+            json!("2023-07-01")
+        );
+        assert_eq!(to_v2(json!("2023-07-01"), "Date"), json!("2023-07-01"));
+    }
+
+    #[test]
+    fn date_times_are_iso_8601() {
+        assert_eq!(
+            to_v2(json!("2023-07-01 12:34:56"), "DateTime"),
+            json!("2023-07-01T12:34:56")
+        );
+        assert_eq!(
+            to_v2(json!("2023-07-01T12:34:56.789"), "DateTime64"),
+            json!("2023-07-01T12:34:56.789")
+        );
+    }
+
+    #[test]
+    fn uuids_are_lowercase() {
+        let uuid = "5E1A5B1C-2F3D-4A6B-8C9D-0E1F2A3B4C5D";
+        assert_eq!(to_v3(json!(uuid), "UUID"), json!(uuid.to_lowercase()));
+        assert_eq!(to_v2(json!(uuid), "UUID"), json!(uuid.to_lowercase()));
+    }
+
+    #[test]
+    fn unknown_types_and_nulls_pass_through() {
+        assert_eq!(to_v3(json!("42"), "Unknown"), json!("42"));
+        assert_eq!(to_v2(json!(1), "Unknown"), json!(1));
+        assert_eq!(to_v3(json!(null), "Int32"), json!(null));
+        assert_eq!(to_v2(json!(null), "DateTime"), json!(null));
+        assert_eq!(to_v3(json!({ "a": 1 }), "JSON"), json!({ "a": 1 }));
+    }
+}
@@ -1,20 +1,192 @@
 use std::collections::HashMap;
 
-use schemars::JsonSchema;
+use jsonschema::JSONSchema;
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
 
+/// Configuration of a data source served by the proxy
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-pub struct Config {}
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The base URL of the v3 connector for this source.
+    /// If not set, the `proxy_target_url` query parameter of the agent URL is used instead.
+    pub target_url: Option<String>,
+    /// The timeout in seconds for each request to the target. Requests do not time out when not set.
+    pub timeout_seconds: Option<u64>,
+    /// Compute aggregate functions the target does not support in the proxy, from the fetched rows.
+    /// Disabled when not set.
+    pub aggregate_emulation: Option<AggregateEmulationConfig>,
+    /// Limits on the queries forwarded to the target, so a single query cannot exhaust the proxy
+    pub limits: Option<QueryLimits>,
+    /// Cache query responses from the target. Disabled when not set.
+    pub query_cache: Option<QueryCacheConfig>,
+    /// Credentials sent with every request to the target
+    pub auth: Option<UpstreamAuth>,
+    /// Headers of incoming requests forwarded to the target, such as `x-hasura-role` or tracing headers.
+    /// Names are case insensitive. Credentials set in `auth` take precedence over forwarded headers.
+    #[serde(default)]
+    pub forward_headers: Vec<String>,
+    /// Forwarded headers that change the response of the target, so responses are cached separately for each of their values.
+    /// Credentials, including forwarded `authorization` and `cookie` headers, always do. Names are case insensitive.
+    #[serde(default)]
+    pub cache_vary_headers: Vec<String>,
+    /// Arguments of the queried collection set from session variables, by argument name.
+    /// Values are session variable names such as `x-hasura-user-id`, read from the headers of incoming requests.
+    #[serde(default)]
+    pub session_arguments: HashMap<String, String>,
+}
+
+/// Aggregate functions computed by the proxy
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateEmulationConfig {
+    /// The maximum number of rows fetched to compute emulated aggregates.
+    /// Queries aggregating over more rows fail, rather than return incorrect results.
+    pub max_rows: u32,
+    /// Count distinct values in the proxy, for targets that ignore or reject distinct column counts.
+    /// v3 connectors do not declare whether they support distinct counts, so this must be enabled explicitly.
+    #[serde(default)]
+    pub distinct_counts: bool,
+}
+
+/// Limits on queries, which do not apply when not set unless they have a default
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct QueryLimits {
+    /// The maximum number of rows returned by the query, and by each relationship field in it.
+    /// Queries returning more rows fail.
+    pub max_rows: Option<u32>,
+    /// The maximum size in bytes of the responses from the target for a query, combined when a query is sent as several.
+    /// Cannot exceed the server wide maximum.
+    pub max_response_size: Option<usize>,
+    /// The maximum depth of nested relationship fields
+    pub max_depth: Option<u32>,
+    /// The maximum number of queries sent to fetch emulated relationship fields one parent row at a time,
+    /// as happens for relationship fields with a limit, offset or aggregates when the target supports neither relationships
+    /// nor foreach queries. Queries needing more fail. Defaults to 100.
+    pub max_relationship_queries: Option<u32>,
+}
+
+impl QueryLimits {
+    const DEFAULT_MAX_RELATIONSHIP_QUERIES: u32 = 100;
+
+    pub fn max_relationship_queries(&self) -> u32 {
+        self.max_relationship_queries
+            .unwrap_or(Self::DEFAULT_MAX_RELATIONSHIP_QUERIES)
+    }
+}
+
+/// Caching of query responses from the target
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct QueryCacheConfig {
+    /// How long responses are cached for, in seconds
+    pub ttl_seconds: u64,
+    /// The maximum size in bytes of a single cached response. Larger responses are not cached.
+    pub max_entry_size: Option<usize>,
+}
+
+/// Credentials for the target. Any combination may be set.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamAuth {
+    /// Sent as an `Authorization: Bearer` header
+    pub bearer_token: Option<Secret>,
+    /// Sent as an `Authorization: Basic` header
+    pub basic: Option<BasicAuth>,
+    /// Additional headers, by header name
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>,
+    /// A client certificate presented to the target over TLS
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// HTTP basic authentication credentials
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    /// The user name
+    pub username: Secret,
+    /// The password
+    pub password: Secret,
+}
+
+/// A PEM encoded certificate and private key
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertificate {
+    /// The PEM encoded certificate chain
+    pub certificate: Secret,
+    /// The PEM encoded PKCS#8 private key
+    pub private_key: Secret,
+}
+
+/// A configuration value that is either given inline, or read from an environment variable of the proxy,
+/// so credentials need not be stored in the source configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Secret {
+    /// The value itself
+    Value {
+        /// The value
+        value: String,
+    },
+    /// The name of an environment variable of the proxy holding the value
+    FromEnv {
+        /// The environment variable name
+        from_env: String,
+    },
+}
+
+impl UpstreamAuth {
+    fn secrets(&self) -> impl Iterator<Item = &Secret> {
+        self.bearer_token
+            .iter()
+            .chain(
+                self.basic
+                    .iter()
+                    .flat_map(|basic| [&basic.username, &basic.password]),
+            )
+            .chain(self.headers.values())
+            .chain(
+                self.client_certificate
+                    .iter()
+                    .flat_map(|client_certificate| {
+                        [
+                            &client_certificate.certificate,
+                            &client_certificate.private_key,
+                        ]
+                    }),
+            )
+    }
+}
+
+impl Secret {
+    pub fn resolve(&self) -> Result<String, ServerError> {
+        match self {
+            Self::Value { value } => Ok(value.to_owned()),
+            Self::FromEnv { from_env } => {
+                std::env::var(from_env).map_err(|_| ServerError::UncaughtError {
+                    details: Some(serde_json::json!({ "from_env": from_env })),
+                    message: format!(
+                        "Environment variable {from_env} referenced by the source configuration is not set"
+                    ),
+                    error_type: ErrorResponseType::UncaughtError,
+                })
+            }
+        }
+    }
+}
 use axum::{
     async_trait,
     extract::{FromRequestParts, Query},
     http::{request::Parts, HeaderName, StatusCode},
 };
 
-use crate::{api::error_response::ErrorResponseType, error::ServerError};
+use crate::{api::error_response::ErrorResponseType, error::ServerError, state::AppState};
 
 static CONFIG_HEADER: HeaderName = HeaderName::from_static("x-hasura-dataconnector-config");
-static SOURCE_HEADER: HeaderName = HeaderName::from_static("x-hasura-dataconnector-sourcename");
+pub static SOURCE_HEADER: HeaderName = HeaderName::from_static("x-hasura-dataconnector-sourcename");
 static PROXY_TARGET_URL: &str = "proxy_target_url";
 
 #[derive(Debug)]
@@ -25,6 +197,14 @@ pub struct SourceConfig(pub Config);
 #[derive(Debug)]
 pub struct ProxyTarget(pub String);
 
+/// The source configuration, if there is one. Unlike `Option<SourceConfig>`, an invalid config header is rejected rather than ignored.
+#[derive(Debug)]
+pub struct OptionalSourceConfig(pub Option<Config>);
+
+/// The target URL, if there is one. Unlike `Option<ProxyTarget>`, a target refused by the allowlist is rejected rather than ignored.
+#[derive(Debug)]
+pub struct OptionalProxyTarget(pub Option<String>);
+
 #[async_trait]
 impl<S: Send + Sync> FromRequestParts<S> for SourceName {
     type Rejection = ServerError;
@@ -49,18 +229,13 @@ impl<S: Send + Sync> FromRequestParts<S> for SourceName {
 }
 
 #[async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for SourceConfig {
+impl FromRequestParts<AppState> for SourceConfig {
     type Rejection = ServerError;
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        if let Some(config_header) = parts.headers.get(&CONFIG_HEADER) {
-            let config: Config =
-                serde_json::from_slice(config_header.as_bytes()).map_err(|err| {
-                    ServerError::UncaughtError {
-                        details: None,
-                        message: "Unable to parse config header".to_string(),
-                        error_type: ErrorResponseType::UncaughtError,
-                    }
-                })?;
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(config) = source_config(parts, state)? {
             Ok(Self(config))
         } else {
             Err(ServerError::UncaughtError {
@@ -73,25 +248,256 @@ impl<S: Send + Sync> FromRequestParts<S> for SourceConfig {
 }
 
 #[async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for ProxyTarget {
+impl FromRequestParts<AppState> for OptionalSourceConfig {
     type Rejection = ServerError;
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let Query(params) = Query::<HashMap<String, String>>::from_request_parts(parts, state)
-            .await
-            .map_err(|err| ServerError::UncaughtError {
-                details: None,
-                message: format!("Unable to parse query parameters: {}", err),
-                error_type: ErrorResponseType::UncaughtError,
-            })?;
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(source_config(parts, state)?))
+    }
+}
 
-        if let Some(proxy_target_url) = params.get(PROXY_TARGET_URL) {
-            Ok(Self(proxy_target_url.to_owned()))
-        } else {
-            Err(ServerError::UncaughtError {
+/// The configuration of a source registered in the config file, or otherwise the one sent by HGE in the config header
+fn source_config(parts: &Parts, state: &AppState) -> Result<Option<Config>, ServerError> {
+    if let Some(registry) = &state.registry {
+        let source_name = parts
+            .headers
+            .get(&SOURCE_HEADER)
+            .and_then(|source_header| source_header.to_str().ok());
+        if let Some(config) = source_name
+            .and_then(|source_name| registry.current().source_config(source_name).cloned())
+        {
+            return Ok(Some(config));
+        }
+    }
+
+    config_from_header(parts, state)
+}
+
+/// Parse the config header, after validating it against the advertised config schema so every problem is reported at once
+fn config_from_header(parts: &Parts, state: &AppState) -> Result<Option<Config>, ServerError> {
+    let config_header = match parts.headers.get(&CONFIG_HEADER) {
+        Some(config_header) => config_header,
+        None => return Ok(None),
+    };
+
+    let config: serde_json::Value =
+        serde_json::from_slice(config_header.as_bytes()).map_err(|err| {
+            ServerError::UncaughtError {
                 details: None,
-                message: format!("Query parameter {PROXY_TARGET_URL} missing"),
+                message: format!("Unable to parse config header: {err}"),
                 error_type: ErrorResponseType::UncaughtError,
+            }
+        })?;
+
+    validate_config(&state.config_schema, &config)?;
+
+    let config = serde_json::from_value(config).map_err(|err| ServerError::UncaughtError {
+        details: None,
+        message: format!("Unable to parse config header: {err}"),
+        error_type: ErrorResponseType::UncaughtError,
+    })?;
+
+    check_secret_env(&config, &state.secret_env_prefixes)?;
+
+    Ok(Some(config))
+}
+
+/// Validate a config against the compiled config schema, reporting every problem with its path
+fn validate_config(
+    config_schema: &JSONSchema,
+    config: &serde_json::Value,
+) -> Result<(), ServerError> {
+    if let Err(errors) = config_schema.validate(config) {
+        let errors: Vec<_> = errors
+            .map(|error| {
+                serde_json::json!({
+                    "path": error.instance_path.to_string(),
+                    "message": error.to_string(),
+                })
             })
+            .collect();
+        return Err(ServerError::UncaughtError {
+            message: format!(
+                "Config header is not valid for the config schema: found {} problem(s)",
+                errors.len()
+            ),
+            details: Some(serde_json::json!({ "errors": errors })),
+            error_type: ErrorResponseType::UncaughtError,
+        });
+    }
+
+    Ok(())
+}
+
+/// Refuse environment variables not allowed by the operator in a config sent by HGE.
+/// Anyone able to send the config header can choose the target, so could otherwise have any variable of the proxy sent to them.
+/// Configurations from the config file are trusted, and not checked.
+fn check_secret_env(config: &Config, allowed_prefixes: &[String]) -> Result<(), ServerError> {
+    let denied: Vec<&str> = config
+        .auth
+        .iter()
+        .flat_map(|auth| auth.secrets())
+        .filter_map(|secret| match secret {
+            Secret::FromEnv { from_env } => Some(from_env.as_str()),
+            Secret::Value { .. } => None,
+        })
+        .filter(|name| {
+            !allowed_prefixes
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_str()))
+        })
+        .collect();
+
+    if denied.is_empty() {
+        Ok(())
+    } else {
+        Err(ServerError::UncaughtError {
+            details: Some(serde_json::json!({
+                "from_env": denied,
+                "allowed_prefixes": allowed_prefixes,
+            })),
+            message: format!(
+                "The source configuration reads environment variables that are not allowed: {}. Only variables starting with a prefix set with --secret-env-prefix can be read",
+                denied.join(", ")
+            ),
+            error_type: ErrorResponseType::UncaughtError,
+        })
+    }
+}
+
+/// The JSON Schema of source configurations, as advertised in the capabilities
+pub fn config_schema() -> RootSchema {
+    schema_for!(Config)
+}
+
+/// The advertised config schema, compiled to validate config headers against
+pub fn compile_config_schema() -> Result<JSONSchema, String> {
+    let schema = serde_json::to_value(config_schema()).map_err(|err| err.to_string())?;
+    JSONSchema::compile(&schema).map_err(|err| err.to_string())
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ProxyTarget {
+    type Rejection = ServerError;
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let target_url = match target_url(parts, state).await? {
+            Some(target_url) => target_url,
+            None => {
+                return Err(ServerError::UncaughtError {
+                    details: None,
+                    message: format!("No target configured: set target_url in the source configuration, or the {PROXY_TARGET_URL} query parameter"),
+                    error_type: ErrorResponseType::UncaughtError,
+                })
+            }
+        };
+
+        state.target_allowlist.check(&target_url).await?;
+
+        Ok(Self(target_url))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for OptionalProxyTarget {
+    type Rejection = ServerError;
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let target_url = target_url(parts, state).await?;
+
+        if let Some(target_url) = &target_url {
+            state.target_allowlist.check(target_url).await?;
         }
+
+        Ok(Self(target_url))
+    }
+}
+
+async fn target_url(parts: &mut Parts, state: &AppState) -> Result<Option<String>, ServerError> {
+    // the target configured for the source takes precedence over the one in the agent URL
+    if let Some(target_url) = source_config(parts, state)?.and_then(|config| config.target_url) {
+        return Ok(Some(target_url));
+    }
+
+    let Query(params) = Query::<HashMap<String, String>>::from_request_parts(parts, state)
+        .await
+        .map_err(|err| ServerError::UncaughtError {
+            details: None,
+            message: format!("Unable to parse query parameters: {}", err),
+            error_type: ErrorResponseType::UncaughtError,
+        })?;
+
+    Ok(params.get(PROXY_TARGET_URL).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{compile_config_schema, validate_config, Config};
+    use crate::error::ServerError;
+
+    /// The paths of the problems found in a config, or None if it is valid
+    fn problems(config: Value) -> Option<Vec<String>> {
+        let config_schema = compile_config_schema().unwrap();
+        let ServerError::UncaughtError { details, .. } =
+            validate_config(&config_schema, &config).err()?;
+        Some(
+            details.unwrap()["errors"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|error| error["path"].as_str().unwrap().to_owned())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn accepts_configs_the_proxy_can_parse() {
+        let config = json!({
+            "target_url": "http://localhost:8100",
+            "timeout_seconds": 30,
+            "aggregate_emulation": { "max_rows": 1000, "distinct_counts": true },
+            "limits": { "max_rows": 100, "max_depth": 3, "max_relationship_queries": 10 },
+            "query_cache": { "ttl_seconds": 60 },
+            "forward_headers": ["x-hasura-role"],
+            "cache_vary_headers": ["x-hasura-role"],
+            "session_arguments": { "user_id": "x-hasura-user-id" },
+        });
+        assert_eq!(problems(config.clone()), None);
+        assert!(serde_json::from_value::<Config>(config).is_ok());
+        assert_eq!(problems(json!({})), None);
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_path() {
+        let mut paths = problems(json!({
+            "limits": { "max_rows": "100" },
+            "query_cache": {},
+        }))
+        .unwrap();
+        paths.sort();
+        // problems inside optional sections may be reported at the section rather than the field
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].starts_with("/limits"), "{paths:?}");
+        assert!(paths[1].starts_with("/query_cache"), "{paths:?}");
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert_eq!(
+            problems(json!({ "target": "http://localhost:8100" })),
+            Some(vec!["".to_owned()])
+        );
+        assert_eq!(
+            problems(json!({ "limits": { "max_row": 100 } })),
+            Some(vec!["/limits".to_owned()])
+        );
     }
 }
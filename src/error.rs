@@ -9,6 +9,7 @@ use axum_macros::FromRequest;
 
 use super::api::error_response::{ErrorResponse, ErrorResponseType};
 
+#[derive(Debug)]
 pub enum ServerError {
     UncaughtError {
         details: Option<serde_json::Value>,
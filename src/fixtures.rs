@@ -0,0 +1,147 @@
+//! A v3 target shared by unit tests: a schema with albums and artists, and the v2 relationships between them
+
+use ndc_client::models;
+use serde_json::{json, Value};
+
+use crate::api::query_request::{TableName, TableRelationships};
+
+pub fn schema() -> models::SchemaResponse {
+    serde_json::from_value(json!({
+        "scalar_types": {
+            "Int32": {
+                "aggregate_functions": {
+                    "max": { "result_type": nullable("Int32") },
+                    "min": { "result_type": nullable("Int32") },
+                    "sum": { "result_type": named("Int64") },
+                    "group_array": { "result_type": array("Int32") },
+                },
+                "comparison_operators": {
+                    "_gt": { "argument_type": named("Int32") },
+                    "_lt": { "argument_type": named("Int32") },
+                    "is_positive": { "argument_type": named("Bool") },
+                    "has_any": { "argument_type": array("Int32") },
+                },
+                "update_operators": {},
+            },
+            "Int64": scalar_type(),
+            "Float64": scalar_type(),
+            "Decimal": scalar_type(),
+            "Bool": scalar_type(),
+            "JSON": scalar_type(),
+            "String": {
+                "aggregate_functions": {},
+                "comparison_operators": {
+                    "like": { "argument_type": named("String") },
+                },
+                "update_operators": {},
+            },
+        },
+        "object_types": {
+            "Album": object_type(json!({
+                "AlbumId": named("Int32"),
+                "Title": named("String"),
+                "ArtistId": named("Int32"),
+                "Price": nullable("Decimal"),
+            })),
+            "Artist": object_type(json!({
+                "ArtistId": named("Int32"),
+                "Name": nullable("String"),
+                "Rating": nullable("Float64"),
+                "Metadata": nullable("JSON"),
+            })),
+        },
+        "tables": [table("Album"), table("Artist")],
+        "functions": [],
+        "procedures": [],
+    }))
+    .expect("fixture schema should deserialize")
+}
+
+/// Capabilities of the target, given the `capabilities` object of the response
+pub fn capabilities(capabilities: Value) -> models::CapabilitiesResponse {
+    serde_json::from_value(json!({
+        "versions": "^0.1.0",
+        "capabilities": capabilities,
+    }))
+    .expect("fixture capabilities should deserialize")
+}
+
+/// `Album.Artist` is an object relationship, and `Artist.Albums` an array relationship, both on `ArtistId`
+pub fn table_relationships() -> Vec<TableRelationships> {
+    serde_json::from_value(json!([
+        {
+            "source_table": ["Album"],
+            "relationships": {
+                "Artist": {
+                    "column_mapping": { "ArtistId": "ArtistId" },
+                    "relationship_type": "object",
+                    "target_table": ["Artist"],
+                },
+            },
+        },
+        {
+            "source_table": ["Artist"],
+            "relationships": {
+                "Albums": {
+                    "column_mapping": { "ArtistId": "ArtistId" },
+                    "relationship_type": "array",
+                    "target_table": ["Album"],
+                },
+            },
+        },
+    ]))
+    .expect("fixture relationships should deserialize")
+}
+
+pub fn table_name(name: &str) -> TableName {
+    vec![name.to_owned()]
+}
+
+fn named(name: &str) -> Value {
+    json!({ "type": "named", "name": name })
+}
+
+fn nullable(name: &str) -> Value {
+    json!({ "type": "nullable", "underlying_type": named(name) })
+}
+
+fn array(name: &str) -> Value {
+    json!({ "type": "array", "element_type": named(name) })
+}
+
+fn scalar_type() -> Value {
+    json!({
+        "aggregate_functions": {},
+        "comparison_operators": {},
+        "update_operators": {},
+    })
+}
+
+fn object_type(fields: Value) -> Value {
+    let fields: serde_json::Map<_, _> = fields
+        .as_object()
+        .expect("fields should be an object")
+        .iter()
+        .map(|(name, r#type)| {
+            (
+                name.to_owned(),
+                json!({ "description": null, "arguments": {}, "type": r#type }),
+            )
+        })
+        .collect();
+    json!({ "description": null, "fields": fields })
+}
+
+fn table(name: &str) -> Value {
+    json!({
+        "name": name,
+        "description": null,
+        "arguments": {},
+        "table_type": name,
+        "insertable_columns": null,
+        "updatable_columns": null,
+        "deletable": false,
+        "uniqueness_constraints": {},
+        "foreign_keys": {},
+    })
+}
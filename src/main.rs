@@ -1,43 +1,209 @@
+mod aggregates;
+mod allowlist;
 mod api;
+mod auth;
+mod cache;
+mod coercion;
 mod config;
 mod error;
+#[cfg(test)]
+mod fixtures;
+mod operators;
+mod registry;
 mod routes;
+mod schema;
+mod server;
+mod state;
+mod tls;
+mod upstream;
 
 use axum::{
+    http::HeaderName,
+    middleware,
     routing::{get, post},
     Router,
 };
-use std::{error::Error, net::SocketAddr};
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use self::routes::*;
+use self::{
+    allowlist::{Network, TargetAllowlist},
+    auth::{InboundAuth, JwtAuth, SharedSecret},
+    cache::{QueryCache, SchemaCache},
+    registry::TargetRegistry,
+    routes::*,
+    server::Listener,
+    state::AppState,
+    tls::TlsFiles,
+};
 use clap::Parser;
 
 #[derive(Parser)]
 struct ServerOptions {
+    /// The address to listen on
+    #[arg(long, env, default_value = "0.0.0.0")]
+    host: IpAddr,
     #[arg(long, env, default_value_t = 8080)]
     port: u16,
+    /// Listen on a Unix domain socket at this path, instead of a TCP port
+    #[arg(long, env, conflicts_with = "tls_certificate")]
+    unix_socket: Option<PathBuf>,
+    /// How long in seconds requests in flight are given to complete after SIGTERM, before the server stops anyway
+    #[arg(long, env, default_value_t = 30)]
+    shutdown_timeout: u64,
+    /// How long in seconds health checks wait for the target's health endpoint
+    #[arg(long, env, default_value_t = 5)]
+    health_check_timeout: u64,
+    /// The maximum size in bytes of a query response from a target. Larger responses fail the query.
+    /// Responses are read in full before they are mapped, so this bounds the memory used by each query.
+    #[arg(long, env, default_value_t = 100 * 1024 * 1024)]
+    max_response_size: usize,
+    /// The maximum total size in bytes of query responses cached for sources with caching enabled
+    #[arg(long, env, default_value_t = 64 * 1024 * 1024)]
+    query_cache_size: usize,
+    /// How long in seconds the capabilities and schema of a target are reused before they are fetched again. 0 disables caching.
+    #[arg(long, env, default_value_t = 60)]
+    schema_cache_ttl: u64,
+    /// A TOML, YAML or JSON file defining targets, and the HGE sources that use them. Reloaded on SIGHUP.
+    #[arg(long, env)]
+    config: Option<PathBuf>,
+    /// URL schemes targets may use
+    #[arg(long, env, value_delimiter = ',', default_value = "http,https")]
+    allowed_target_schemes: Vec<String>,
+    /// Host names targets may use. `*.example.com` allows any subdomain of example.com.
+    /// Any host is allowed if neither hosts nor networks are set.
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_target_hosts: Vec<String>,
+    /// Networks targets may be in, in CIDR notation such as `10.0.0.0/8`. Host names are resolved to check them.
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_target_networks: Vec<Network>,
+    /// Prefixes of the environment variables that data source configurations sent by HGE may read secrets from, such as `GDC_SECRET_`.
+    /// Targets in the config file may read any variable. No variables can be read from data source configurations if not set.
+    #[arg(long, env, value_delimiter = ',')]
+    secret_env_prefix: Vec<String>,
+    /// A secret callers must send in the shared secret header
+    #[arg(long, env)]
+    shared_secret: Option<String>,
+    /// The header callers send the shared secret in
+    #[arg(long, env, default_value = "x-proxy-secret")]
+    shared_secret_header: HeaderName,
+    /// A JWKS file with the keys that sign the bearer tokens callers must send
+    #[arg(long, env)]
+    jwks_file: Option<PathBuf>,
+    /// The audience bearer tokens must be issued for
+    #[arg(long, env)]
+    jwt_audience: Option<String>,
+    /// The issuer of bearer tokens
+    #[arg(long, env)]
+    jwt_issuer: Option<String>,
+    /// A PEM certificate chain to serve HTTPS with. Reloaded when the file changes.
+    #[arg(long, env, requires = "tls_private_key")]
+    tls_certificate: Option<PathBuf>,
+    /// The PEM private key of the TLS certificate
+    #[arg(long, env, requires = "tls_certificate")]
+    tls_private_key: Option<PathBuf>,
+    /// A PEM CA certificate that clients must present a certificate signed by
+    #[arg(long, env, requires = "tls_certificate")]
+    tls_client_ca: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let options = ServerOptions::parse();
 
-    let router = Router::new()
+    let registry = options
+        .config
+        .map(TargetRegistry::load)
+        .transpose()?
+        .map(Arc::new);
+    if let Some(registry) = &registry {
+        tokio::spawn(registry::reload_on_hangup(registry.clone()));
+    }
+
+    let inbound_auth = Arc::new(InboundAuth {
+        shared_secret: options.shared_secret.map(|secret| SharedSecret {
+            header: options.shared_secret_header,
+            secret,
+        }),
+        jwt: options
+            .jwks_file
+            .map(|jwks_file| JwtAuth::load(&jwks_file, options.jwt_audience, options.jwt_issuer))
+            .transpose()?,
+    });
+
+    let mut router = Router::new()
         .route("/capabilities", get(get_capabilities))
         .route("/schema", get(get_schema))
         .route("/query", post(post_query))
         .route("/mutation", post(post_mutation))
         .route("/raw", post(post_raw))
         .route("/explain", post(post_explain))
-        .route("/health", get(get_health));
+        .route("/metrics", get(get_metrics));
+
+    let mut health = get(get_health);
+
+    // health checks come from the orchestrator, which has no credentials, unless they check a source
+    if inbound_auth.is_enabled() {
+        router = router.route_layer(middleware::from_fn_with_state(
+            inbound_auth.clone(),
+            auth::authenticate,
+        ));
+        health = health.route_layer(middleware::from_fn_with_state(
+            inbound_auth,
+            auth::authenticate_source_requests,
+        ));
+    }
+
+    let router = router.route("/health", health).with_state(AppState {
+        max_response_size: options.max_response_size,
+        query_cache: Arc::new(QueryCache::new(options.query_cache_size)),
+        schema_cache: Arc::new(SchemaCache::new(Duration::from_secs(
+            options.schema_cache_ttl,
+        ))),
+        registry,
+        target_allowlist: Arc::new(TargetAllowlist {
+            schemes: options.allowed_target_schemes,
+            hosts: options.allowed_target_hosts,
+            networks: options.allowed_target_networks,
+        }),
+        health_check_timeout: Duration::from_secs(options.health_check_timeout),
+        config_schema: Arc::new(config::compile_config_schema()?),
+        secret_env_prefixes: options.secret_env_prefix.into(),
+    });
+
+    let adresss = SocketAddr::new(options.host, options.port);
 
-    let adresss = format!("0.0.0.0:{}", options.port).parse()?;
+    let tls_files =
+        options
+            .tls_certificate
+            .zip(options.tls_private_key)
+            .map(|(certificate, private_key)| TlsFiles {
+                certificate,
+                private_key,
+                client_ca: options.tls_client_ca,
+            });
 
-    println!("Starting server on {}", &adresss);
+    let listener = if let Some(path) = options.unix_socket {
+        Listener::Unix(path)
+    } else if let Some(tls_files) = tls_files {
+        let tls_config = tls_files.load()?;
+        tokio::spawn(tls::reload_on_change(tls_config.clone(), tls_files));
+        Listener::Tls(adresss, tls_config)
+    } else {
+        Listener::Tcp(adresss)
+    };
 
-    axum::Server::bind(&adresss)
-        .serve(router.into_make_service())
-        .await?;
+    server::serve(
+        router,
+        listener,
+        Duration::from_secs(options.shutdown_timeout),
+    )
+    .await?;
 
     Ok(())
 }
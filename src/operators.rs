@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+
+use ndc_client::models;
+use serde_json::Value;
+
+use crate::{api::error_response::ErrorResponseType, error::ServerError};
+
+/// v2 built-in binary comparison operators, and the v3 operator names we accept as their equivalent, in order of preference.
+/// Equality is not listed here, as v3 has a built-in equality operator.
+static BUILTIN_BINARY_OPERATORS: &[(&str, &[&str])] = &[
+    ("less_than", &["_lt", "lt", "less_than"]),
+    ("less_than_or_equal", &["_lte", "lte", "less_than_or_equal"]),
+    ("greater_than", &["_gt", "gt", "greater_than"]),
+    (
+        "greater_than_or_equal",
+        &["_gte", "gte", "greater_than_or_equal"],
+    ),
+];
+
+/// v3 operator names equivalent to the v2 built-in equality operator
+static EQUAL_OPERATORS: &[&str] = &["_eq", "eq", "equal"];
+
+/// v2 array operators that are the negation of the built-in `in` operator
+static NOT_IN_OPERATORS: &[&str] = &["not_in", "nin"];
+
+/// Maps v2 comparison operators to the comparison operators declared by the v3 target, per scalar type.
+pub struct ComparisonOperators {
+    /// A map from scalar type names to a map from v2 operator names to v3 operators
+    scalar_types: HashMap<String, HashMap<String, Operator>>,
+}
+
+struct Operator {
+    /// The v3 operator name
+    name: String,
+    argument_type: Option<ArgumentType>,
+}
+
+enum ArgumentType {
+    Scalar(String),
+    Array(String),
+}
+
+impl ComparisonOperators {
+    pub fn new(schema: &models::SchemaResponse) -> Self {
+        let scalar_types = schema
+            .scalar_types
+            .iter()
+            .map(|(scalar_type_name, scalar_type)| {
+                let mut operators = HashMap::new();
+
+                // custom operators are advertised and sent to us using their v3 name
+                for (operator_name, operator) in scalar_type.comparison_operators.iter() {
+                    if !is_builtin_equivalent(operator_name) {
+                        operators.insert(
+                            operator_name.to_owned(),
+                            Operator {
+                                name: operator_name.to_owned(),
+                                argument_type: argument_type(&operator.argument_type),
+                            },
+                        );
+                    }
+                }
+
+                for (v2_operator, v3_operators) in BUILTIN_BINARY_OPERATORS {
+                    if let Some((v3_operator, operator)) = v3_operators.iter().find_map(|name| {
+                        scalar_type
+                            .comparison_operators
+                            .get(*name)
+                            .map(|operator| (name, operator))
+                    }) {
+                        operators.insert(
+                            v2_operator.to_string(),
+                            Operator {
+                                name: v3_operator.to_string(),
+                                argument_type: argument_type(&operator.argument_type),
+                            },
+                        );
+                    }
+                }
+
+                (scalar_type_name.to_owned(), operators)
+            })
+            .collect();
+
+        Self { scalar_types }
+    }
+
+    pub fn binary_operator(
+        &self,
+        scalar_type: &str,
+        operator: &str,
+    ) -> Result<models::BinaryComparisonOperator, ServerError> {
+        if operator == "equal" {
+            return Ok(models::BinaryComparisonOperator::Equal);
+        }
+
+        match self.operators(scalar_type)?.get(operator) {
+            Some(Operator { name, .. }) => Ok(models::BinaryComparisonOperator::Other {
+                name: name.to_owned(),
+            }),
+            None => Err(self.unsupported_operator(scalar_type, operator)),
+        }
+    }
+
+    /// Map a v2 unary operator. v3 only has `is_null` built in, so other operators are either rewritten using `not`,
+    /// or mapped to a v3 operator of the same name that takes a boolean argument, which we set to `true`
+    pub fn unary_expression(
+        &self,
+        scalar_type: &str,
+        operator: &str,
+        column: models::ComparisonTarget,
+    ) -> Result<models::Expression, ServerError> {
+        match operator {
+            "is_null" => Ok(models::Expression::UnaryComparisonOperator {
+                column: Box::new(column),
+                operator: Box::new(models::UnaryComparisonOperator::IsNull),
+            }),
+            "is_not_null" => Ok(models::Expression::Not {
+                expression: Box::new(models::Expression::UnaryComparisonOperator {
+                    column: Box::new(column),
+                    operator: Box::new(models::UnaryComparisonOperator::IsNull),
+                }),
+            }),
+            _ => match self.operators(scalar_type)?.get(operator) {
+                Some(Operator {
+                    name,
+                    argument_type: Some(ArgumentType::Scalar(argument_type)),
+                }) if is_boolean_type(argument_type) => {
+                    Ok(models::Expression::BinaryComparisonOperator {
+                        column: Box::new(column),
+                        operator: Box::new(models::BinaryComparisonOperator::Other {
+                            name: name.to_owned(),
+                        }),
+                        value: Box::new(models::ComparisonValue::Scalar {
+                            value: Value::Bool(true),
+                        }),
+                    })
+                }
+                _ => Err(self.unsupported_operator(scalar_type, operator)),
+            },
+        }
+    }
+
+    /// Map a v2 binary array operator. v3 only has `in` built in, so other operators are either rewritten using `not`,
+    /// or mapped to a v3 operator of the same name that takes an array argument
+    pub fn array_expression(
+        &self,
+        scalar_type: &str,
+        operator: &str,
+        column: models::ComparisonTarget,
+        values: Vec<Value>,
+    ) -> Result<models::Expression, ServerError> {
+        if operator == "in" {
+            return Ok(in_expression(column, values));
+        }
+        if NOT_IN_OPERATORS.contains(&operator) {
+            return Ok(models::Expression::Not {
+                expression: Box::new(in_expression(column, values)),
+            });
+        }
+
+        match self.operators(scalar_type)?.get(operator) {
+            Some(Operator {
+                name,
+                argument_type: Some(ArgumentType::Array(_)),
+            }) => Ok(models::Expression::BinaryComparisonOperator {
+                column: Box::new(column),
+                operator: Box::new(models::BinaryComparisonOperator::Other {
+                    name: name.to_owned(),
+                }),
+                value: Box::new(models::ComparisonValue::Scalar {
+                    value: Value::Array(values),
+                }),
+            }),
+            _ => Err(self.unsupported_operator(scalar_type, operator)),
+        }
+    }
+
+    fn operators(&self, scalar_type: &str) -> Result<&HashMap<String, Operator>, ServerError> {
+        self.scalar_types
+            .get(scalar_type)
+            .ok_or_else(|| ServerError::UncaughtError {
+                details: Some(serde_json::json!({ "scalar_type": scalar_type })),
+                message: format!("Unknown scalar type {scalar_type}"),
+                error_type: ErrorResponseType::UncaughtError,
+            })
+    }
+
+    fn unsupported_operator(&self, scalar_type: &str, operator: &str) -> ServerError {
+        let supported_operators = self
+            .scalar_types
+            .get(scalar_type)
+            .map(|operators| operators.keys().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        ServerError::UncaughtError {
+            details: Some(serde_json::json!({
+                "operator": operator,
+                "scalar_type": scalar_type,
+                "supported_operators": supported_operators,
+            })),
+            message: format!(
+                "Comparison operator {operator} is not supported for scalar type {scalar_type}"
+            ),
+            error_type: ErrorResponseType::UncaughtError,
+        }
+    }
+}
+
+/// Whether a v3 operator is the equivalent of a v2 built-in operator.
+/// Such operators should not be advertised to HGE as custom operators, as HGE will use the built-in instead.
+pub fn is_builtin_equivalent(v3_operator: &str) -> bool {
+    EQUAL_OPERATORS.contains(&v3_operator)
+        || BUILTIN_BINARY_OPERATORS
+            .iter()
+            .any(|(_, v3_operators)| v3_operators.contains(&v3_operator))
+}
+
+fn argument_type(r#type: &models::Type) -> Option<ArgumentType> {
+    match r#type {
+        models::Type::Named { name } => Some(ArgumentType::Scalar(name.to_owned())),
+        models::Type::Nullable { underlying_type } => argument_type(underlying_type),
+        models::Type::Array { element_type } => match argument_type(element_type) {
+            Some(ArgumentType::Scalar(name)) => Some(ArgumentType::Array(name)),
+            _ => None,
+        },
+    }
+}
+
+fn is_boolean_type(scalar_type: &str) -> bool {
+    matches!(scalar_type.to_lowercase().as_str(), "bool" | "boolean")
+}
+
+fn in_expression(column: models::ComparisonTarget, values: Vec<Value>) -> models::Expression {
+    models::Expression::BinaryArrayComparisonOperator {
+        column: Box::new(column),
+        operator: Box::new(models::BinaryArrayComparisonOperator::In),
+        values: values
+            .into_iter()
+            .map(|value| models::ComparisonValue::Scalar { value })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_client::models;
+    use serde_json::{json, Value};
+
+    use super::{is_builtin_equivalent, ComparisonOperators};
+    use crate::{error::ServerError, fixtures};
+
+    fn operators() -> ComparisonOperators {
+        ComparisonOperators::new(&fixtures::schema())
+    }
+
+    fn operator_name(operator: Result<models::BinaryComparisonOperator, ServerError>) -> String {
+        match operator.unwrap() {
+            models::BinaryComparisonOperator::Equal => "equal".to_owned(),
+            models::BinaryComparisonOperator::Other { name } => name,
+        }
+    }
+
+    #[test]
+    fn maps_builtin_operators_to_the_declared_v3_operators() {
+        let operators = operators();
+        assert_eq!(
+            operator_name(operators.binary_operator("Int32", "equal")),
+            "equal"
+        );
+        assert_eq!(
+            operator_name(operators.binary_operator("Int32", "less_than")),
+            "_lt"
+        );
+        assert_eq!(
+            operator_name(operators.binary_operator("Int32", "greater_than")),
+            "_gt"
+        );
+        // v3 names of built-in operators are not custom operators
+        assert!(operators.binary_operator("Int32", "_gt").is_err());
+    }
+
+    #[test]
+    fn maps_custom_operators_by_their_v3_name() {
+        assert_eq!(
+            operator_name(operators().binary_operator("String", "like")),
+            "like"
+        );
+    }
+
+    #[test]
+    fn reports_the_supported_operators_of_undeclared_ones() {
+        let ServerError::UncaughtError { details, .. } = operators()
+            .binary_operator("String", "less_than")
+            .unwrap_err();
+        let details = details.unwrap();
+        assert_eq!(details["operator"], "less_than");
+        assert_eq!(details["supported_operators"], serde_json::json!(["like"]));
+    }
+
+    #[test]
+    fn reports_unknown_scalar_types() {
+        let ServerError::UncaughtError { message, .. } = operators()
+            .binary_operator("Geometry", "less_than")
+            .unwrap_err();
+        assert_eq!(message, "Unknown scalar type Geometry");
+    }
+
+    fn column() -> models::ComparisonTarget {
+        models::ComparisonTarget::Column {
+            name: "ArtistId".to_owned(),
+            path: vec![],
+        }
+    }
+
+    fn is_null(expression: &models::Expression) -> bool {
+        matches!(
+            expression,
+            models::Expression::UnaryComparisonOperator { operator, .. }
+                if matches!(**operator, models::UnaryComparisonOperator::IsNull)
+        )
+    }
+
+    /// The v3 operator name and scalar argument of a binary comparison
+    fn binary_comparison(expression: &models::Expression) -> Option<(&str, &Value)> {
+        match expression {
+            models::Expression::BinaryComparisonOperator {
+                operator, value, ..
+            } => match (&**operator, &**value) {
+                (
+                    models::BinaryComparisonOperator::Other { name },
+                    models::ComparisonValue::Scalar { value },
+                ) => Some((name.as_str(), value)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn in_values(expression: &models::Expression) -> Option<Vec<&Value>> {
+        match expression {
+            models::Expression::BinaryArrayComparisonOperator { values, .. } => values
+                .iter()
+                .map(|value| match value {
+                    models::ComparisonValue::Scalar { value } => Some(value),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+
+    fn negated(expression: &models::Expression) -> Option<&models::Expression> {
+        match expression {
+            models::Expression::Not { expression } => Some(expression),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn maps_null_checks_to_the_builtin_is_null() {
+        let operators = operators();
+        let expression = operators
+            .unary_expression("Int32", "is_null", column())
+            .unwrap();
+        assert!(is_null(&expression));
+
+        let expression = operators
+            .unary_expression("Int32", "is_not_null", column())
+            .unwrap();
+        assert!(negated(&expression).map_or(false, is_null));
+    }
+
+    #[test]
+    fn maps_custom_unary_operators_to_boolean_operators_set_to_true() {
+        let expression = operators()
+            .unary_expression("Int32", "is_positive", column())
+            .unwrap();
+        assert_eq!(
+            binary_comparison(&expression),
+            Some(("is_positive", &json!(true)))
+        );
+    }
+
+    #[test]
+    fn refuses_unary_use_of_operators_without_a_boolean_argument() {
+        let operators = operators();
+        assert!(operators
+            .unary_expression("Int32", "greater_than", column())
+            .is_err());
+        assert!(operators
+            .unary_expression("Int32", "has_any", column())
+            .is_err());
+    }
+
+    #[test]
+    fn maps_in_and_its_negations_to_the_builtin_in() {
+        let operators = operators();
+        let values = vec![json!(1), json!(2)];
+        let expression = operators
+            .array_expression("Int32", "in", column(), values.clone())
+            .unwrap();
+        assert_eq!(in_values(&expression), Some(vec![&json!(1), &json!(2)]));
+
+        for operator in ["not_in", "nin"] {
+            let expression = operators
+                .array_expression("Int32", operator, column(), values.clone())
+                .unwrap();
+            assert_eq!(
+                negated(&expression).and_then(in_values),
+                Some(vec![&json!(1), &json!(2)]),
+                "{operator}"
+            );
+        }
+    }
+
+    #[test]
+    fn maps_custom_array_operators_to_operators_taking_arrays() {
+        let operators = operators();
+        let expression = operators
+            .array_expression("Int32", "has_any", column(), vec![json!(1), json!(2)])
+            .unwrap();
+        assert_eq!(
+            binary_comparison(&expression),
+            Some(("has_any", &json!([1, 2])))
+        );
+
+        assert!(operators
+            .array_expression("Int32", "is_positive", column(), vec![json!(true)])
+            .is_err());
+    }
+
+    #[test]
+    fn recognises_v3_equivalents_of_builtin_operators() {
+        for operator in ["_eq", "eq", "equal", "_lt", "lte", "greater_than", "_gte"] {
+            assert!(is_builtin_equivalent(operator), "{operator}");
+        }
+        for operator in ["like", "is_positive", "_neq"] {
+            assert!(!is_builtin_equivalent(operator), "{operator}");
+        }
+    }
+}
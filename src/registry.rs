@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::Config;
+
+/// Targets defined in the proxy's config file, and the HGE sources that use them
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    /// Target configurations, by target name
+    #[serde(default)]
+    pub targets: HashMap<String, Config>,
+    /// Target names, by HGE source name
+    #[serde(default)]
+    pub sources: HashMap<String, String>,
+}
+
+impl Registry {
+    /// Read a registry from a TOML, YAML or JSON file, depending on its extension
+    fn from_file(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let invalid = |err: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {err}", path.display()),
+            )
+        };
+
+        let registry: Self = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|err| invalid(&err))?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|err| invalid(&err))?,
+            Some("json") => serde_json::from_str(&contents).map_err(|err| invalid(&err))?,
+            _ => {
+                return Err(invalid(
+                    &"unknown config file format, expected a .toml, .yaml, .yml or .json file",
+                ))
+            }
+        };
+
+        for (source_name, target_name) in &registry.sources {
+            if !registry.targets.contains_key(target_name) {
+                return Err(invalid(&format!(
+                    "source {source_name} refers to undefined target {target_name}"
+                )));
+            }
+        }
+
+        Ok(registry)
+    }
+
+    /// The configuration of the target registered for a source
+    pub fn source_config(&self, source_name: &str) -> Option<&Config> {
+        self.sources
+            .get(source_name)
+            .and_then(|target_name| self.targets.get(target_name))
+    }
+}
+
+/// The registry loaded from the config file. Reloading replaces it as a whole, so requests never see a partial update.
+pub struct TargetRegistry {
+    path: PathBuf,
+    registry: RwLock<Arc<Registry>>,
+}
+
+impl TargetRegistry {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let registry = Registry::from_file(&path)?;
+        Ok(Self {
+            path,
+            registry: RwLock::new(Arc::new(registry)),
+        })
+    }
+
+    /// Read the config file again. The previous registry is kept if the file is invalid.
+    pub fn reload(&self) -> io::Result<()> {
+        let registry = Registry::from_file(&self.path)?;
+        *self.registry.write().unwrap() = Arc::new(registry);
+        Ok(())
+    }
+
+    pub fn current(&self) -> Arc<Registry> {
+        self.registry.read().unwrap().clone()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Reload the registry whenever the process receives SIGHUP
+pub async fn reload_on_hangup(registry: Arc<TargetRegistry>) -> io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+
+    while hangups.recv().await.is_some() {
+        match registry.reload() {
+            Ok(()) => println!("Reloaded {}", registry.path().display()),
+            Err(err) => eprintln!("Unable to reload config file, keeping the previous one: {err}"),
+        }
+    }
+
+    Ok(())
+}
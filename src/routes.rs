@@ -1,5 +1,6 @@
 mod get_capabilities;
 mod get_health;
+mod get_metrics;
 mod get_schema;
 mod post_explain;
 mod post_mutation;
@@ -8,6 +9,7 @@ mod post_raw;
 
 pub use get_capabilities::get_capabilities;
 pub use get_health::get_health;
+pub use get_metrics::get_metrics;
 pub use get_schema::get_schema;
 pub use post_explain::post_explain;
 pub use post_mutation::post_mutation;
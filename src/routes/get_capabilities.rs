@@ -1,59 +1,153 @@
-use axum::{extract::State, Json};
+use std::collections::HashMap;
+
+use axum::{extract::State, http::HeaderMap, Json};
 use indexmap::IndexMap;
 use ndc_client::models;
-use schemars::schema_for;
 
 use crate::{
-    config::ProxyTarget,
-    error::ServerError,
-    {
-        api::capabilities_response::{
-            Capabilities, CapabilitiesResponse, ColumnNullability, ComparisonCapabilities,
-            ConfigSchemaResponse, DataSchemaCapabilities, GraphQlType, QueryCapabilities,
-            ScalarTypeCapabilities, SubqueryComparisonCapabilities,
-        },
-        config::Config,
+    aggregates::emulated_aggregate_functions,
+    api::capabilities_response::{
+        Capabilities, CapabilitiesResponse, ColumnNullability, ComparisonCapabilities,
+        ConfigSchemaResponse, DataSchemaCapabilities, QueryCapabilities, ScalarTypeCapabilities,
+        SubqueryComparisonCapabilities,
     },
+    coercion,
+    config::{config_schema, OptionalProxyTarget, OptionalSourceConfig},
+    error::ServerError,
+    operators::is_builtin_equivalent,
+    schema::scalar_type_name,
+    state::AppState,
+    upstream::UpstreamClient,
 };
 
+use super::post_query::supports_relation_comparisons;
+
 #[axum_macros::debug_handler]
 pub async fn get_capabilities(
-    ProxyTarget(base_url): ProxyTarget,
+    State(state): State<AppState>,
+    OptionalProxyTarget(target_url): OptionalProxyTarget,
+    OptionalSourceConfig(config): OptionalSourceConfig,
+    headers: HeaderMap,
 ) -> Result<Json<CapabilitiesResponse>, ServerError> {
+    // capabilities are requested once per agent, not per source, so there may be no target to ask
+    let base_url = match target_url {
+        Some(base_url) => base_url,
+        None => {
+            return Ok(Json(
+                registry_capabilities(&state, &headers)
+                    .await
+                    .unwrap_or_else(default_capabilities),
+            ))
+        }
+    };
+
+    let config = config.as_ref();
+    let client = UpstreamClient::new(config, &headers, &state.target_allowlist)?;
+    let max_response_size = config.map_or(state.max_response_size, |config| {
+        state.max_response_size(config)
+    });
+
     let url = format!("{}/capabilities", base_url);
-    let client = reqwest::Client::new();
-    let request = client.get(&url).send().await?.text().await?;
-    let capabilities_response = serde_json::from_str(&request)?;
+    let capabilities_response = client.get_json(&url, max_response_size).await?;
 
     let url = format!("{}/schema", base_url);
-    let client = reqwest::Client::new();
-    let request = client.get(&url).send().await?.text().await?;
-    let schema_response = serde_json::from_str(&request)?;
+    let schema_response = client.get_json(&url, max_response_size).await?;
 
     let response = map_capabilities(capabilities_response, schema_response);
 
     Ok(Json(response))
 }
 
+/// The capabilities of the targets in the config file, if they are all the same, so scalar types can be advertised without a target in the agent URL.
+/// If the targets differ, or one of them cannot be reached, no single set of capabilities applies to every source.
+async fn registry_capabilities(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Option<CapabilitiesResponse> {
+    let registry = state.registry.as_ref()?.current();
+    let mut shared: Option<(CapabilitiesResponse, serde_json::Value)> = None;
+
+    for config in registry.targets.values() {
+        let base_url = config.target_url.as_ref()?;
+        state.target_allowlist.check(base_url).await.ok()?;
+        let client = UpstreamClient::new(Some(config), headers, &state.target_allowlist).ok()?;
+        let target_schema = client
+            .target_schema(
+                base_url,
+                &state.schema_cache,
+                state.max_response_size(config),
+            )
+            .await
+            .ok()?;
+
+        let capabilities = map_capabilities(
+            target_schema.capabilities.clone(),
+            target_schema.schema.clone(),
+        );
+        let value = serde_json::to_value(&capabilities).ok()?;
+        match &shared {
+            Some((_, shared_value)) if *shared_value != value => return None,
+            Some(_) => {}
+            None => shared = Some((capabilities, value)),
+        }
+    }
+
+    shared.map(|(capabilities, _)| capabilities)
+}
+
+/// Capabilities advertised when no target is known. Scalar types come from the target's schema, so none are advertised.
+fn default_capabilities() -> CapabilitiesResponse {
+    CapabilitiesResponse {
+        display_name: Some("Hasura GDC v2 proxy for v3".to_string()),
+        release_name: None,
+        config_schemas: ConfigSchemaResponse {
+            config_schema: config_schema(),
+            other_schemas: IndexMap::new(),
+        },
+        capabilities: Capabilities {
+            comparisons: None,
+            data_schema: Some(DataSchemaCapabilities {
+                column_nullability: Some(ColumnNullability::NullableAndNonNullable),
+                supports_foreign_keys: Some(false),
+                supports_primary_keys: Some(true),
+            }),
+            datasets: None,
+            explain: None,
+            metrics: None,
+            mutations: None,
+            queries: Some(QueryCapabilities { foreach: None }),
+            raw: None,
+            // relationships are emulated by the proxy when the target does not support them
+            relationships: Some(serde_json::json!({})),
+            scalar_types: IndexMap::new(),
+            subscriptions: None,
+        },
+    }
+}
+
 fn map_capabilities(
     capabilities: models::CapabilitiesResponse,
     schema: models::SchemaResponse,
 ) -> CapabilitiesResponse {
+    // aggregate functions the proxy can compute itself when aggregate emulation is enabled for the source
+    let emulated_aggregate_functions: HashMap<_, _> = schema
+        .scalar_types
+        .keys()
+        .map(|key| (key.to_owned(), emulated_aggregate_functions(&schema, key)))
+        .collect();
+
     CapabilitiesResponse {
         display_name: Some("Hasura GDC v2 proxy for v3".to_string()),
         release_name: Some(capabilities.versions),
         config_schemas: ConfigSchemaResponse {
-            config_schema: schema_for!(Config),
+            config_schema: config_schema(),
             other_schemas: IndexMap::new(),
         },
         capabilities: Capabilities {
             comparisons: Some(ComparisonCapabilities {
+                // exists expressions over unrelated tables are always supported, but relationships only with relation comparisons
                 subquery: Some(SubqueryComparisonCapabilities {
-                    supports_relations: capabilities
-                        .capabilities
-                        .query
-                        .as_ref()
-                        .map(|query| query.relation_comparisons.is_some()),
+                    supports_relations: Some(supports_relation_comparisons(&capabilities)),
                 }),
             }),
             data_schema: Some(DataSchemaCapabilities {
@@ -72,44 +166,47 @@ fn map_capabilities(
                     foreach: query.foreach,
                 }),
             raw: None,
-            relationships: capabilities.capabilities.relationships,
+            // relationships are emulated by the proxy when the target does not support them
+            relationships: Some(
+                capabilities
+                    .capabilities
+                    .relationships
+                    .unwrap_or_else(|| serde_json::json!({})),
+            ),
             scalar_types: IndexMap::from_iter(schema.scalar_types.into_iter().map(
                 |(key, scalar_type)| {
+                    let graphql_type = coercion::graphql_type(&key);
+                    let emulated_aggregate_functions = emulated_aggregate_functions
+                        .get(&key)
+                        .cloned()
+                        .unwrap_or_default();
                     (
                         key,
                         ScalarTypeCapabilities {
                             aggregate_functions: Some(IndexMap::from_iter(
-                                scalar_type.aggregate_functions.into_iter().map(
-                                    |(key, aggregate_function)| {
-                                        (
-                                            key,
-                                            match aggregate_function.result_type {
-                                                models::Type::Named { name } => name,
-                                                _ => panic!(
-                                                    "Unsupported aggregate function return type"
-                                                ),
-                                            },
-                                        )
-                                    },
-                                ),
+                                scalar_type
+                                    .aggregate_functions
+                                    .into_iter()
+                                    // v2 aggregates return scalars, so functions returning arrays are not advertised
+                                    .filter_map(|(key, aggregate_function)| {
+                                        scalar_type_name(&aggregate_function.result_type)
+                                            .map(|name| (key, name.to_owned()))
+                                    })
+                                    .chain(emulated_aggregate_functions),
                             )),
                             comparison_operators: Some(IndexMap::from_iter(
-                                scalar_type.comparison_operators.into_iter().map(
-                                    |(key, comparison_operator)| {
-                                        (
-                                            key,
-                                            match comparison_operator.argument_type {
-                                                models::Type::Named { name } => name,
-                                                _ => panic!(
-                                                    "Unsupported comparison operator argument type"
-                                                ),
-                                            },
-                                        )
-                                    },
-                                ),
+                                scalar_type
+                                    .comparison_operators
+                                    .into_iter()
+                                    .filter(|(key, _)| !is_builtin_equivalent(key))
+                                    // operators taking arrays are used by HGE as binary array operators, which are not advertised
+                                    .filter_map(|(key, comparison_operator)| {
+                                        scalar_type_name(&comparison_operator.argument_type)
+                                            .map(|name| (key, name.to_owned()))
+                                    }),
                             )),
                             update_column_operators: Some(IndexMap::new()),
-                            graphql_type: GraphQlType::String,
+                            graphql_type,
                         },
                     )
                 },
@@ -118,3 +215,81 @@ fn map_capabilities(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::map_capabilities;
+    use crate::{api::capabilities_response::ScalarTypeCapabilities, fixtures};
+
+    fn supports_relations(query_capabilities: Value) -> Option<bool> {
+        let capabilities = map_capabilities(
+            fixtures::capabilities(json!({ "query": query_capabilities })),
+            fixtures::schema(),
+        );
+        capabilities
+            .capabilities
+            .comparisons
+            .and_then(|comparisons| comparisons.subquery)
+            .and_then(|subquery| subquery.supports_relations)
+    }
+
+    #[test]
+    fn advertises_subqueries_over_relations_only_with_relation_comparisons() {
+        assert_eq!(supports_relations(json!({})), Some(false));
+        assert_eq!(
+            supports_relations(json!({ "relation_comparisons": {} })),
+            Some(true)
+        );
+    }
+
+    fn scalar_type(scalar_type: &str) -> ScalarTypeCapabilities {
+        let mut capabilities = map_capabilities(
+            fixtures::capabilities(json!({ "query": {} })),
+            fixtures::schema(),
+        );
+        capabilities
+            .capabilities
+            .scalar_types
+            .swap_remove(scalar_type)
+            .unwrap()
+    }
+
+    #[test]
+    fn advertises_custom_operators_but_not_builtin_equivalents() {
+        let comparison_operators = scalar_type("Int32").comparison_operators.unwrap();
+        assert!(comparison_operators.get("_gt").is_none());
+        assert!(comparison_operators.get("_lt").is_none());
+        assert_eq!(
+            comparison_operators.get("is_positive").map(String::as_str),
+            Some("Bool")
+        );
+
+        let comparison_operators = scalar_type("String").comparison_operators.unwrap();
+        assert_eq!(
+            comparison_operators.get("like").map(String::as_str),
+            Some("String")
+        );
+    }
+
+    #[test]
+    fn advertises_scalar_result_and_argument_types_only() {
+        let int32 = scalar_type("Int32");
+
+        // nullable result types are advertised as their scalar type, and array results not at all
+        let aggregate_functions = int32.aggregate_functions.unwrap();
+        assert_eq!(
+            aggregate_functions.get("max").map(String::as_str),
+            Some("Int32")
+        );
+        assert_eq!(
+            aggregate_functions.get("sum").map(String::as_str),
+            Some("Int64")
+        );
+        assert!(aggregate_functions.get("group_array").is_none());
+
+        // operators taking arrays are used as binary array operators, which are not advertised
+        assert!(int32.comparison_operators.unwrap().get("has_any").is_none());
+    }
+}
@@ -1,17 +1,82 @@
-use axum::{http::StatusCode, TypedHeader};
-use axum_extra::extract::WithRejection;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
 
 use crate::{
-    config::{ProxyTarget, SourceConfig, SourceName},
+    api::error_response::{ErrorResponse, ErrorResponseType},
+    config::{Config, OptionalSourceConfig, ProxyTarget, SourceName},
     error::ServerError,
+    state::AppState,
+    upstream::UpstreamClient,
 };
 
 #[axum_macros::debug_handler]
 pub async fn get_health(
-    _proxy_target: Option<ProxyTarget>,
-    _source_name: Option<SourceName>,
-    _config: Option<SourceConfig>,
-) -> StatusCode {
-    // todo: if source_name and config provided, check if that specific source is healthy
-    StatusCode::NO_CONTENT
+    State(state): State<AppState>,
+    source_name: Option<SourceName>,
+    proxy_target: Result<ProxyTarget, ServerError>,
+    source_config: Result<OptionalSourceConfig, ServerError>,
+    headers: HeaderMap,
+) -> Response {
+    // the registry and query cache are set up before the server starts listening,
+    // so without a source to check, the proxy is ready as soon as it answers
+    let source_name = match source_name {
+        Some(SourceName(source_name)) => source_name,
+        None => return StatusCode::NO_CONTENT.into_response(),
+    };
+
+    // a source without a valid target or configuration is reported, rather than checked without them
+    let (base_url, config) = match (proxy_target, source_config) {
+        (Ok(ProxyTarget(base_url)), Ok(OptionalSourceConfig(config))) => (base_url, config),
+        (Err(err), _) | (_, Err(err)) => return err.into_response(),
+    };
+
+    let result = check_target(&state, &base_url, config.as_ref(), &headers).await;
+
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(message) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse {
+                details: Some(serde_json::json!({
+                    "source_name": source_name,
+                    "target_url": base_url,
+                })),
+                message: format!("Source {source_name} is unhealthy: {message}"),
+                error_type: ErrorResponseType::UncaughtError,
+            }),
+        )
+            .into_response(),
+    }
+}
+
+/// Call the health endpoint of the target, with the source's credentials
+async fn check_target(
+    state: &AppState,
+    base_url: &str,
+    config: Option<&Config>,
+    headers: &HeaderMap,
+) -> Result<(), String> {
+    let client = UpstreamClient::new(config, headers, &state.target_allowlist)
+        .map_err(|ServerError::UncaughtError { message, .. }| message)?;
+
+    let url = format!("{}/health", base_url);
+    let response = client
+        .get(&url)
+        .timeout(state.health_check_timeout)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Target responded with status {}",
+            response.status()
+        ))
+    }
 }
@@ -0,0 +1,16 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+
+use crate::state::AppState;
+
+#[axum_macros::debug_handler]
+pub async fn get_metrics(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.query_cache.metrics(),
+    )
+        .into_response()
+}
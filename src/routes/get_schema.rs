@@ -1,4 +1,8 @@
-use axum::{extract::State, http::StatusCode, Json, TypedHeader};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json, TypedHeader,
+};
 
 use axum_extra::extract::WithRejection;
 use ndc_client::models;
@@ -8,18 +12,23 @@ use crate::{
     api::schema_response::{ColumnInfo, SchemaResponse, TableInfo},
     config::{ProxyTarget, SourceConfig, SourceName},
     error::ServerError,
+    state::AppState,
+    upstream::UpstreamClient,
 };
 
 #[axum_macros::debug_handler]
 pub async fn get_schema(
+    State(state): State<AppState>,
     ProxyTarget(base_url): ProxyTarget,
     SourceName(source_name): SourceName,
     SourceConfig(config): SourceConfig,
+    headers: HeaderMap,
 ) -> Result<Json<SchemaResponse>, ServerError> {
     let url = format!("{}/schema", base_url);
-    let client = reqwest::Client::new();
-    let request = client.get(&url).send().await?.text().await?;
-    let response = serde_json::from_str(&request)?;
+    let client = UpstreamClient::new(Some(&config), &headers, &state.target_allowlist)?;
+    let response = client
+        .get_json(&url, state.max_response_size(&config))
+        .await?;
 
     let response = map_response(response);
 
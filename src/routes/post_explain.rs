@@ -1,37 +1,17 @@
-use axum::{extract::State, Json};
-use axum_extra::extract::WithRejection;
-use ndc_client::models;
+use axum::Json;
 
 use crate::{
-    api::{explain_response::ExplainResponse, query_request::QueryRequest},
-    config::{ProxyTarget, SourceConfig, SourceName},
+    api::{error_response::ErrorResponseType, explain_response::ExplainResponse},
     error::ServerError,
 };
 
-use super::post_query::map_request;
-
+/// The explain capability is not advertised, as v3 explain responses have no v2 equivalent yet,
+/// so HGE should not call this. Fail clearly if it does.
 #[axum_macros::debug_handler]
-pub async fn post_explain(
-    ProxyTarget(base_url): ProxyTarget,
-    SourceName(source_name): SourceName,
-    SourceConfig(config): SourceConfig,
-    WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, ServerError>,
-) -> Result<Json<ExplainResponse>, ServerError> {
-    let url = format!("{}/explain", base_url);
-
-    let client = reqwest::Client::new();
-
-    let request = map_request(request);
-    let body = serde_json::to_string(&request)?;
-
-    let request = client.post(&url).body(body).send().await?.text().await?;
-
-    let response = serde_json::from_str(&request)?;
-    let response = map_response(response);
-
-    Ok(Json(response))
-}
-
-fn map_response(response: models::ExplainResponse) -> ExplainResponse {
-    todo!()
+pub async fn post_explain() -> Result<Json<ExplainResponse>, ServerError> {
+    Err(ServerError::UncaughtError {
+        details: None,
+        message: "Explain is not supported by the proxy".to_string(),
+        error_type: ErrorResponseType::UncaughtError,
+    })
 }
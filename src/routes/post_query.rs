@@ -1,58 +1,324 @@
-use std::collections::HashMap;
+mod emulated_aggregates;
+mod emulated_relationships;
+mod encoding;
+mod limits;
+mod relationships;
+mod simplify;
+mod validation;
 
-use axum::{extract::State, http::response, Json};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
 use axum_extra::extract::WithRejection;
 use indexmap::IndexMap;
 use ndc_client::models;
 
 use crate::{
     api::{
+        error_response::ErrorResponseType,
         query_request::{
             Aggregate, ComparisonColumn, ComparisonValue, ExistsInTable, Expression, Field,
             OrderBy, OrderByElement, OrderByRelation, OrderByTarget, OrderDirection, Query,
-            QueryRequest, Relationship, RelationshipType, TableRelationships,
+            QueryRequest, Relationship, RelationshipType, TableName,
         },
-        query_response::{QueryResponse, RowFieldValue},
     },
-    config::{ProxyTarget, SourceConfig, SourceName},
+    cache::{CacheControl, QueryCache, TargetSchema},
+    coercion,
+    config::{Config, ProxyTarget, SourceConfig, SourceName},
     error::ServerError,
+    operators::ComparisonOperators,
+    schema::table_name,
+    state::AppState,
+    upstream::{read_body, UpstreamClient},
 };
 
+use self::emulated_aggregates::AggregatePlan;
+use self::relationships::{relationship_key, RelationshipLookup};
+
 #[axum_macros::debug_handler]
 pub async fn post_query(
+    State(state): State<AppState>,
     ProxyTarget(base_url): ProxyTarget,
     SourceName(source_name): SourceName,
     SourceConfig(config): SourceConfig,
+    headers: HeaderMap,
     WithRejection(Json(request), _): WithRejection<Json<QueryRequest>, ServerError>,
-) -> Result<Json<QueryResponse>, ServerError> {
+) -> Result<Response, ServerError> {
     let url = format!("{}/query", base_url);
 
-    let client = reqwest::Client::new();
+    let client = UpstreamClient::new(Some(&config), &headers, &state.target_allowlist)?;
+    let upstream = Upstream {
+        client: &client,
+        url: &url,
+        max_response_size: state.max_response_size(&config),
+        received: AtomicUsize::new(0),
+        cache: config
+            .query_cache
+            .as_ref()
+            .map(|cache_config| UpstreamCache {
+                cache: &state.query_cache,
+                ttl: Duration::from_secs(cache_config.ttl_seconds),
+                max_entry_size: cache_config.max_entry_size,
+                cache_control: CacheControl::from_headers(&headers),
+            }),
+    };
+
+    let target_schema = client
+        .target_schema(&base_url, &state.schema_cache, upstream.max_response_size)
+        .await?;
 
     let is_foreach = request.foreach.is_some();
+    let query = request.query.clone();
 
-    let request = map_request(request);
+    let mut request = request;
+    let aggregates_request = split_aggregates(&mut request.query).map(|query| QueryRequest {
+        foreach: request.foreach.clone(),
+        query,
+        table: request.table.clone(),
+        table_relationships: request.table_relationships.clone(),
+    });
 
-    let request = client
-        .post(&url)
-        .json(&request)
-        .send()
-        .await?
-        .text()
+    let mut response =
+        execute_request(&upstream, request, &target_schema, &config, &headers).await?;
+    if let Some(aggregates_request) = aggregates_request {
+        let aggregates_response = execute_request(
+            &upstream,
+            aggregates_request,
+            &target_schema,
+            &config,
+            &headers,
+        )
         .await?;
+        for (row_set, aggregates_row_set) in response.0.iter_mut().zip(aggregates_response.0) {
+            row_set.aggregates = aggregates_row_set.aggregates;
+        }
+    }
+    for row_set in response.0.iter_mut() {
+        merge_split_aggregates(row_set, &query);
+    }
+
+    let body = encoding::encode_response(response, &query, is_foreach);
+
+    Ok(([(header::CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+/// Map a v2 request to v3 and execute it, emulating the aggregates and relationships the target does not support
+async fn execute_request(
+    upstream: &Upstream<'_>,
+    request: QueryRequest,
+    target_schema: &TargetSchema,
+    config: &Config,
+    headers: &HeaderMap,
+) -> Result<models::QueryResponse, ServerError> {
+    let TargetSchema {
+        capabilities,
+        schema,
+    } = target_schema;
+    let query_limits = config.limits.clone().unwrap_or_default();
+
+    let mut request = map_request(request, schema, capabilities, config)?;
+    set_session_arguments(&mut request, schema, config, headers)?;
+    let emulate_relationships = capabilities.capabilities.relationships.is_none()
+        && !request.table_relationships.is_empty();
+    limits::limit_query(&mut request.query, &query_limits, emulate_relationships)?;
+    let aggregate_plan = AggregatePlan::new(&mut request, schema, config)?;
 
-    let response = serde_json::from_str(&request)?;
-    let response = map_response(response, is_foreach);
+    let mut response = if emulate_relationships {
+        let supports_foreach = capabilities
+            .capabilities
+            .query
+            .as_ref()
+            .and_then(|query| query.foreach.as_ref())
+            .is_some();
+        let emulation = emulated_relationships::Emulation::new(
+            supports_foreach,
+            query_limits.max_relationship_queries(),
+        );
+        emulated_relationships::execute_query(upstream, request, &emulation).await?
+    } else {
+        upstream.query(&request).await?
+    };
+
+    for row_set in response.0.iter_mut() {
+        aggregate_plan.apply(row_set)?;
+        if let Some(max_rows) = query_limits.max_rows {
+            limits::check_rows(row_set, max_rows)?;
+        }
+    }
 
-    Ok(Json(response))
+    Ok(response)
 }
 
-fn table_name(table: Vec<String>) -> String {
-    // we expect always exactly one string in the table name string vector
-    table.into_iter().next().unwrap()
+/// The query endpoint of the v3 target
+struct Upstream<'a> {
+    client: &'a UpstreamClient,
+    url: &'a str,
+    /// The maximum combined size of the responses received for the incoming request
+    max_response_size: usize,
+    /// The size of the responses received so far, including those read from the cache
+    received: AtomicUsize,
+    cache: Option<UpstreamCache<'a>>,
 }
 
-pub fn map_request(request: QueryRequest) -> models::QueryRequest {
+/// How responses from the target are cached, for sources with caching enabled
+struct UpstreamCache<'a> {
+    cache: &'a QueryCache,
+    ttl: Duration,
+    max_entry_size: Option<usize>,
+    cache_control: CacheControl,
+}
+
+impl Upstream<'_> {
+    async fn query(
+        &self,
+        request: &models::QueryRequest,
+    ) -> Result<models::QueryResponse, ServerError> {
+        let cache_key = match &self.cache {
+            Some(cache) => {
+                let key = QueryCache::key(self.url, self.client.credentials(), request)?;
+                if let Some(body) = cache.cache.get(key, &cache.cache_control) {
+                    self.receive(body.len())?;
+                    return Ok(serde_json::from_slice(&body)?);
+                }
+                Some(key)
+            }
+            None => None,
+        };
+
+        let response = self.client.post(self.url).json(request).send().await?;
+        let body = read_body(response, self.max_response_size).await?;
+        self.receive(body.len())?;
+        let response = serde_json::from_slice(&body)?;
+
+        if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+            if cache
+                .max_entry_size
+                .map_or(true, |max_entry_size| body.len() <= max_entry_size)
+            {
+                cache
+                    .cache
+                    .insert(key, body.into(), cache.ttl, &cache.cache_control);
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Count a response towards the maximum size, so queries sent as several, such as those emulating relationships,
+    /// cannot receive more in total than a single query could
+    fn receive(&self, size: usize) -> Result<(), ServerError> {
+        let received = self.received.fetch_add(size, Ordering::Relaxed) + size;
+        if received > self.max_response_size {
+            let max_response_size = self.max_response_size;
+            return Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({ "max_response_size": max_response_size })),
+                message: format!(
+                    "Responses from the target exceeded the maximum combined size of {max_response_size} bytes"
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Set arguments from the session variables of the incoming request, as configured for the source.
+/// They are set on every collection of the request that declares them: the queried collection, and the targets of its relationships,
+/// so related rows are scoped by the session as much as the root rows are.
+fn set_session_arguments(
+    request: &mut models::QueryRequest,
+    schema: &models::SchemaResponse,
+    config: &Config,
+    headers: &HeaderMap,
+) -> Result<(), ServerError> {
+    let values = session_argument_values(config, headers)?;
+    let declared_arguments = |table: &str| {
+        let table_info = schema
+            .tables
+            .iter()
+            .find(|table_info| table_info.name == table);
+        values.iter().filter(move |(argument, _)| {
+            table_info.map_or(false, |table_info| {
+                table_info.arguments.contains_key(*argument)
+            })
+        })
+    };
+
+    request.arguments = declared_arguments(&request.table)
+        .map(|(argument, value)| {
+            (
+                argument.to_owned(),
+                models::Argument::Literal {
+                    value: value.to_owned(),
+                },
+            )
+        })
+        .collect();
+
+    for relationship in request.table_relationships.values_mut() {
+        relationship
+            .arguments
+            .extend(
+                declared_arguments(&relationship.target_table).map(|(argument, value)| {
+                    (
+                        argument.to_owned(),
+                        models::RelationshipArgument::Literal {
+                            value: value.to_owned(),
+                        },
+                    )
+                }),
+            );
+    }
+
+    Ok(())
+}
+
+fn session_argument_values(
+    config: &Config,
+    headers: &HeaderMap,
+) -> Result<HashMap<String, serde_json::Value>, ServerError> {
+    config
+        .session_arguments
+        .iter()
+        .map(|(argument, session_variable)| {
+            let value = headers
+                .get(session_variable.as_str())
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| ServerError::UncaughtError {
+                    details: Some(serde_json::json!({
+                        "argument": argument,
+                        "session_variable": session_variable,
+                    })),
+                    message: format!(
+                        "Session variable {session_variable}, required for argument {argument}, is not set"
+                    ),
+                    error_type: ErrorResponseType::UncaughtError,
+                })?;
+            Ok((
+                argument.to_owned(),
+                serde_json::Value::String(value.to_owned()),
+            ))
+        })
+        .collect()
+}
+
+pub fn map_request(
+    request: QueryRequest,
+    schema: &models::SchemaResponse,
+    capabilities: &models::CapabilitiesResponse,
+    config: &Config,
+) -> Result<models::QueryRequest, ServerError> {
+    validation::validate_request(&request, schema, config)?;
+
     let QueryRequest {
         foreach,
         table,
@@ -63,15 +329,26 @@ pub fn map_request(request: QueryRequest) -> models::QueryRequest {
     let variables = foreach.map(|foreach| {
         foreach
             .into_iter()
-            .map(|map| HashMap::from_iter(map.into_iter().map(|(key, value)| (key, value.value))))
+            .map(|map| {
+                HashMap::from_iter(
+                    map.into_iter()
+                        .map(|(key, value)| (key, coercion::to_v3(value.value, &value.value_type))),
+                )
+            })
             .collect()
     });
 
-    models::QueryRequest {
-        table: table_name(table.clone()),
+    let context = MappingContext {
+        relationships: RelationshipLookup::new(&table_relationships),
+        operators: ComparisonOperators::new(schema),
+        capabilities,
+    };
+
+    Ok(models::QueryRequest {
+        table: table_name(&table),
         arguments: HashMap::new(),
         variables,
-        query: map_query(query, table_name(table), &table_relationships),
+        query: simplify::simplify_query(map_query(query, table, &context)?),
         table_relationships: HashMap::from_iter(table_relationships.into_iter().flat_map(
             |relationship| {
                 relationship.relationships.into_iter().map(
@@ -82,19 +359,15 @@ pub fn map_request(request: QueryRequest) -> models::QueryRequest {
                             target_table,
                         } = relationship_info;
                         (
-                            format!(
-                                "{}.{}",
-                                table_name(relationship.source_table.clone()),
-                                relationship_name
-                            ),
+                            relationship_key(&relationship.source_table, &relationship_name),
                             models::Relationship {
                                 column_mapping: HashMap::from_iter(column_mapping.into_iter()),
                                 relationship_type: match relationship_type {
                                     RelationshipType::Object => models::RelationshipType::Object,
                                     RelationshipType::Array => models::RelationshipType::Array,
                                 },
-                                source_table_or_type: table_name(relationship.source_table.clone()),
-                                target_table: table_name(target_table),
+                                source_table_or_type: table_name(&relationship.source_table),
+                                target_table: table_name(&target_table),
                                 arguments: HashMap::new(),
                             },
                         )
@@ -102,14 +375,14 @@ pub fn map_request(request: QueryRequest) -> models::QueryRequest {
                 )
             },
         )),
-    }
+    })
 }
 
 fn map_query(
     query: Query,
-    table: String,
-    table_relationships: &Vec<TableRelationships>,
-) -> models::Query {
+    table: TableName,
+    context: &MappingContext,
+) -> Result<models::Query, ServerError> {
     let Query {
         aggregates,
         aggregates_limit,
@@ -120,66 +393,15 @@ fn map_query(
         selection,
     } = query;
 
-    let order_by = order_by.map(|order_by| {
-        let OrderBy {
-            elements,
-            relations,
-        } = order_by;
-        models::OrderBy {
-            elements: elements
-                .into_iter()
-                .map(|element| {
-                    let OrderByElement {
-                        order_direction,
-                        target,
-                        target_path,
-                    } = element;
-
-                    models::OrderByElement {
-                        order_direction: match order_direction {
-                            OrderDirection::Asc => models::OrderDirection::Asc,
-                            OrderDirection::Desc => models::OrderDirection::Desc,
-                        },
-                        target: match target {
-                            OrderByTarget::StarCountAggregate => {
-                                models::OrderByTarget::StarCountAggregate {
-                                    path: map_order_by_path(
-                                        target_path,
-                                        relations.to_owned(),
-                                        table.to_owned(),
-                                        table_relationships,
-                                    ),
-                                }
-                            }
-                            OrderByTarget::SingleColumnAggregate {
-                                column,
-                                function,
-                                result_type,
-                            } => models::OrderByTarget::SingleColumnAggregate {
-                                column,
-                                function,
-                                path: map_order_by_path(
-                                    target_path,
-                                    relations.to_owned(),
-                                    table.to_owned(),
-                                    table_relationships,
-                                ),
-                            },
-                            OrderByTarget::Column { column } => models::OrderByTarget::Column {
-                                name: column,
-                                path: map_order_by_path_without_predicate(
-                                    target_path,
-                                    relations.to_owned(),
-                                    table.to_owned(),
-                                    table_relationships,
-                                ),
-                            },
-                        },
-                    }
-                })
-                .collect(),
-        }
-    });
+    // v3 queries have a single limit, applying to both rows and aggregates.
+    // Queries limiting them differently are split by `split_aggregates` before they get here
+    let limit = match fields {
+        Some(_) => limit,
+        None => aggregates_limit,
+    };
+    let order_by = order_by
+        .map(|order_by| map_order_by(order_by, &table, context))
+        .transpose()?;
     let aggregates = aggregates.map(|aggregates| {
         HashMap::from_iter(aggregates.into_iter().map(|(key, aggregate)| {
             (
@@ -198,197 +420,359 @@ fn map_query(
             )
         }))
     });
-    let fields = fields.map(|fields| {
-        HashMap::from_iter(fields.into_iter().map(|(key, field)| {
-            (
-                key,
+    let fields = fields
+        .map(|fields| {
+            let mut mapped_fields = HashMap::new();
+            for (key, field) in fields {
                 match field {
                     Field::Column {
                         column,
                         column_type,
-                    } => models::Field::Column {
-                        column,
-                        arguments: HashMap::new(),
-                    },
+                    } => {
+                        mapped_fields.insert(
+                            key,
+                            models::Field::Column {
+                                column,
+                                arguments: HashMap::new(),
+                            },
+                        );
+                    }
                     Field::Relationship {
-                        query,
+                        mut query,
                         relationship,
-                    } => models::Field::Relationship {
-                        query: Box::new(map_query(
-                            query,
-                            get_target_table(&table, &relationship, table_relationships),
-                            table_relationships,
-                        )),
-                        relationship: format!("{}.{}", table, relationship),
-                        arguments: HashMap::new(),
-                    },
-                },
-            )
-        }))
-    });
-    models::Query {
+                    } => {
+                        let target_table = &context
+                            .relationships
+                            .get(&table, &relationship)?
+                            .target_table;
+                        let relationship_field = |query| {
+                            Ok::<_, ServerError>(models::Field::Relationship {
+                                query: Box::new(map_query(
+                                    query,
+                                    target_table.to_owned(),
+                                    context,
+                                )?),
+                                relationship: relationship_key(&table, &relationship),
+                                arguments: HashMap::new(),
+                            })
+                        };
+                        // the aggregates are fetched by a hidden field, and moved back by `merge_split_aggregates`
+                        if let Some(aggregates_query) = split_aggregates(&mut query) {
+                            mapped_fields.insert(
+                                aggregates_field(&key),
+                                relationship_field(aggregates_query)?,
+                            );
+                        }
+                        mapped_fields.insert(key, relationship_field(query)?);
+                    }
+                }
+            }
+            Ok::<_, ServerError>(mapped_fields)
+        })
+        .transpose()?;
+    Ok(models::Query {
         aggregates,
         fields,
         limit,
         offset,
         order_by,
         predicate: selection
-            .map(|selection| map_expression(selection, &table, table_relationships)),
-    }
+            .map(|selection| map_expression(selection, &Scope::root(table.to_owned()), context))
+            .transpose()?,
+    })
 }
 
-fn map_order_by_path(
-    path: Vec<String>,
-    relations: IndexMap<String, OrderByRelation>,
-    table: String,
-    table_relationships: &Vec<TableRelationships>,
-) -> Vec<models::PathElementWithPredicate> {
-    let mut mapped_path: Vec<models::PathElementWithPredicate> = vec![];
+/// Whether a query limits its rows and its aggregates differently, as HGE does for select permissions with a row limit
+fn has_separate_aggregates_limit(query: &Query) -> bool {
+    query.fields.is_some() && query.aggregates.is_some() && query.limit != query.aggregates_limit
+}
 
-    let mut relations = relations;
-    let mut source_table = table;
-    for segment in path {
-        let relation = relations
-            .get(&segment)
-            .expect("order by path segments should reference valid relationships");
+/// v3 queries have a single limit for both rows and aggregates,
+/// so the aggregates of a query limiting them differently are moved to a separate query with the aggregates limit,
+/// over the same rows as the original query.
+fn split_aggregates(query: &mut Query) -> Option<Query> {
+    if !has_separate_aggregates_limit(query) {
+        return None;
+    }
 
-        let target_table = get_target_table(&source_table, &segment, table_relationships);
+    Some(Query {
+        aggregates: query.aggregates.take(),
+        aggregates_limit: query.aggregates_limit.take(),
+        fields: None,
+        limit: None,
+        offset: query.offset,
+        order_by: query.order_by.clone(),
+        selection: query.selection.clone(),
+    })
+}
 
-        mapped_path.push(models::PathElementWithPredicate {
-            relationship: format!("{}.{}", source_table, segment),
-            arguments: HashMap::new(),
-            predicate: if let Some(selection) = &relation.selection {
-                Box::new(map_expression(
-                    selection.to_owned(),
-                    &target_table,
-                    table_relationships,
-                ))
-            } else {
-                // hack: predicate is not optional, so default to empty "And" expression, which evaluates to true.
-                Box::new(models::Expression::And {
-                    expressions: vec![],
-                })
-            },
-        });
+/// The alias of the hidden relationship field fetching the aggregates split from a relationship field
+fn aggregates_field(alias: &str) -> String {
+    format!("__split_aggregates_{alias}")
+}
 
-        source_table = target_table;
-        relations = relation.subrelations.to_owned();
+/// Move the aggregates fetched by hidden fields back into the row sets of the relationship fields they were split from
+fn merge_split_aggregates(row_set: &mut models::RowSet, query: &Query) {
+    for (alias, field) in query.fields.iter().flatten() {
+        let query = match field {
+            Field::Relationship { query, .. } => query,
+            Field::Column { .. } => continue,
+        };
+        let is_split = has_separate_aggregates_limit(query);
+
+        for row in row_set.rows.iter_mut().flatten() {
+            let aggregates = if is_split {
+                match row.remove(&aggregates_field(alias)) {
+                    Some(models::RowFieldValue::Relationship { rows }) => rows.aggregates,
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            if let Some(models::RowFieldValue::Relationship { rows }) = row.get_mut(alias) {
+                if is_split {
+                    rows.aggregates = aggregates;
+                }
+                merge_split_aggregates(rows, query);
+            }
+        }
     }
+}
+
+fn map_order_by(
+    order_by: OrderBy,
+    table: &TableName,
+    context: &MappingContext,
+) -> Result<models::OrderBy, ServerError> {
+    let OrderBy {
+        elements,
+        relations,
+    } = order_by;
+
+    Ok(models::OrderBy {
+        elements: elements
+            .into_iter()
+            .map(|element| map_order_by_element(element, &relations, table, context))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn map_order_by_element(
+    element: OrderByElement,
+    relations: &IndexMap<String, OrderByRelation>,
+    table: &TableName,
+    context: &MappingContext,
+) -> Result<models::OrderByElement, ServerError> {
+    let OrderByElement {
+        order_direction,
+        target,
+        target_path,
+    } = element;
+
+    let path = map_order_by_path(&target_path, relations, table, context)?;
+
+    let target = match target {
+        OrderByTarget::StarCountAggregate => {
+            context.require_order_by_aggregate(&target_path)?;
+
+            models::OrderByTarget::StarCountAggregate {
+                path: path
+                    .into_iter()
+                    .map(OrderByPathSegment::with_predicate)
+                    .collect(),
+            }
+        }
+        OrderByTarget::SingleColumnAggregate {
+            column, function, ..
+        } => {
+            // the aggregate function and its result type are checked by validation
+            context.require_order_by_aggregate(&target_path)?;
 
-    mapped_path
+            models::OrderByTarget::SingleColumnAggregate {
+                column,
+                function,
+                path: path
+                    .into_iter()
+                    .map(OrderByPathSegment::with_predicate)
+                    .collect(),
+            }
+        }
+        OrderByTarget::Column { column } => {
+            // v3 paths to a column cannot carry predicates, and must only follow object relationships
+            for segment in &path {
+                if matches!(segment.relationship_type, RelationshipType::Array) {
+                    return Err(ServerError::UncaughtError {
+                        details: Some(serde_json::json!({
+                            "target_path": target_path,
+                            "relationship": segment.relationship,
+                        })),
+                        message: format!(
+                            "Cannot order by column {column} across array relationship {}",
+                            segment.relationship
+                        ),
+                        error_type: ErrorResponseType::UncaughtError,
+                    });
+                }
+                if segment.predicate.is_some() {
+                    return Err(ServerError::UncaughtError {
+                        details: Some(serde_json::json!({
+                            "target_path": target_path,
+                            "relationship": segment.relationship,
+                        })),
+                        message: format!(
+                            "Cannot order by column {column} with a predicate on relationship {}",
+                            segment.relationship
+                        ),
+                        error_type: ErrorResponseType::UncaughtError,
+                    });
+                }
+            }
+
+            models::OrderByTarget::Column {
+                name: column,
+                path: path
+                    .into_iter()
+                    .map(OrderByPathSegment::without_predicate)
+                    .collect(),
+            }
+        }
+    };
+
+    Ok(models::OrderByElement {
+        order_direction: match order_direction {
+            OrderDirection::Asc => models::OrderDirection::Asc,
+            OrderDirection::Desc => models::OrderDirection::Desc,
+        },
+        target,
+    })
+}
+
+struct OrderByPathSegment {
+    relationship: String,
+    relationship_type: RelationshipType,
+    predicate: Option<models::Expression>,
+}
+
+impl OrderByPathSegment {
+    fn with_predicate(self) -> models::PathElementWithPredicate {
+        models::PathElementWithPredicate {
+            relationship: self.relationship,
+            arguments: HashMap::new(),
+            // predicate is not optional, so default to an empty "And" expression, which evaluates to true.
+            predicate: Box::new(self.predicate.unwrap_or(models::Expression::And {
+                expressions: vec![],
+            })),
+        }
+    }
+    fn without_predicate(self) -> models::PathElement {
+        models::PathElement {
+            relationship: self.relationship,
+            arguments: HashMap::new(),
+        }
+    }
 }
-fn map_order_by_path_without_predicate(
-    path: Vec<String>,
-    relations: IndexMap<String, OrderByRelation>,
-    table: String,
-    table_relationships: &Vec<TableRelationships>,
-) -> Vec<models::PathElement> {
-    let mut mapped_path: Vec<models::PathElement> = vec![];
+
+/// Follow an order by path through the order by relations, mapping the predicate on each relation
+fn map_order_by_path(
+    path: &[String],
+    relations: &IndexMap<String, OrderByRelation>,
+    table: &TableName,
+    context: &MappingContext,
+) -> Result<Vec<OrderByPathSegment>, ServerError> {
+    let mut mapped_path = vec![];
 
     let mut relations = relations;
-    let mut source_table = table;
+    let mut source_table = table.to_owned();
     for segment in path {
         let relation = relations
-            .get(&segment)
-            .expect("order by path segments should reference valid relationships");
+            .get(segment)
+            .ok_or_else(|| ServerError::UncaughtError {
+                details: Some(serde_json::json!({
+                    "target_path": path,
+                    "segment": segment,
+                })),
+                message: format!(
+                    "Order by path segment {segment} does not reference an order by relation"
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            })?;
 
-        let target_table = get_target_table(&source_table, &segment, table_relationships);
+        let relationship = context.relationships.get(&source_table, segment)?;
+        let target_table = relationship.target_table.to_owned();
 
-        mapped_path.push(models::PathElement {
-            relationship: format!("{}.{}", source_table, segment),
-            arguments: HashMap::new(),
+        let predicate = relation
+            .selection
+            .as_ref()
+            .map(|selection| {
+                map_expression(
+                    selection.to_owned(),
+                    &Scope::nested(target_table.to_owned()),
+                    context,
+                )
+            })
+            .transpose()?;
+
+        mapped_path.push(OrderByPathSegment {
+            relationship: relationship_key(&source_table, segment),
+            relationship_type: relationship.relationship_type.to_owned(),
+            predicate,
         });
 
         source_table = target_table;
-        relations = relation.subrelations.to_owned();
+        relations = &relation.subrelations;
     }
 
-    mapped_path
-}
-
-fn get_target_table(
-    source_table: &str,
-    relationship: &str,
-    table_relationships: &Vec<TableRelationships>,
-) -> String {
-    let source_table = table_relationships
-        .iter()
-        .find(|table_relationships| {
-            table_relationships
-                .source_table
-                .first()
-                .expect("tables names have at least one string")
-                == source_table
-        })
-        .expect("all relationships should exist in table_relationships");
-
-    let relationship = source_table
-        .relationships
-        .get(relationship)
-        .expect("relationships should exist in table relationships");
-
-    table_name(relationship.target_table.to_owned())
+    Ok(mapped_path)
 }
 
 fn map_expression(
     expression: Expression,
-    table: &str,
-    table_relationships: &Vec<TableRelationships>,
-) -> models::Expression {
-    match expression {
+    scope: &Scope,
+    context: &MappingContext,
+) -> Result<models::Expression, ServerError> {
+    Ok(match expression {
         Expression::And { expressions } => models::Expression::And {
             expressions: expressions
                 .into_iter()
-                .map(|expression| map_expression(expression, table, table_relationships))
-                .collect(),
+                .map(|expression| map_expression(expression, scope, context))
+                .collect::<Result<_, _>>()?,
         },
         Expression::Or { expressions } => models::Expression::Or {
             expressions: expressions
                 .into_iter()
-                .map(|expression| map_expression(expression, table, table_relationships))
-                .collect(),
+                .map(|expression| map_expression(expression, scope, context))
+                .collect::<Result<_, _>>()?,
         },
         Expression::Not { expression } => models::Expression::Not {
-            expression: Box::new(map_expression(*expression, table, table_relationships)),
+            expression: Box::new(map_expression(*expression, scope, context)?),
         },
         Expression::UnaryComparisonOperator { column, operator } => {
-            models::Expression::UnaryComparisonOperator {
-                column: Box::new(map_comparison_column(
-                    column,
-                    table.to_string(),
-                    table_relationships,
-                )),
-                operator: match operator.as_str() {
-                    "is_null" => Box::new(models::UnaryComparisonOperator::IsNull),
-                    _ => panic!("Unsupported unary comparison operator"),
-                },
-            }
+            context.operators.unary_expression(
+                &column.column_type.to_owned(),
+                &operator,
+                map_comparison_column(column, scope, context)?,
+            )?
         }
         Expression::BinaryComparisonOperator {
             column,
             operator,
             value,
         } => models::Expression::BinaryComparisonOperator {
-            column: Box::new(map_comparison_column(
-                column,
-                table.to_string(),
-                table_relationships,
-            )),
-            operator: match operator.as_str() {
-                "equal" => Box::new(models::BinaryComparisonOperator::Equal),
-                _ => Box::new(models::BinaryComparisonOperator::Other { name: operator }),
-            },
+            operator: Box::new(
+                context
+                    .operators
+                    .binary_operator(&column.column_type, &operator)?,
+            ),
+            column: Box::new(map_comparison_column(column, scope, context)?),
             value: Box::new(match value {
                 ComparisonValue::ScalarValueComparison { value, value_type } => {
-                    models::ComparisonValue::Scalar { value }
+                    models::ComparisonValue::Scalar {
+                        value: coercion::to_v3(value, &value_type),
+                    }
                 }
                 ComparisonValue::AnotherColumnComparison { column } => {
                     models::ComparisonValue::Column {
-                        column: Box::new(map_comparison_column(
-                            column,
-                            table.to_string(),
-                            table_relationships,
-                        )),
+                        column: Box::new(map_comparison_column(column, scope, context)?),
                     }
                 }
             }),
@@ -398,143 +782,199 @@ fn map_expression(
             operator,
             value_type,
             values,
-        } => models::Expression::BinaryArrayComparisonOperator {
-            column: Box::new(map_comparison_column(
-                column,
-                table.to_string(),
-                table_relationships,
-            )),
-            operator: match operator.as_str() {
-                "in" => Box::new(models::BinaryArrayComparisonOperator::In),
-                _ => panic!("Unsupported binary array comparison operator"),
-            },
-            values: values
+        } => context.operators.array_expression(
+            &column.column_type.to_owned(),
+            &operator,
+            map_comparison_column(column, scope, context)?,
+            values
                 .into_iter()
-                .map(|value| models::ComparisonValue::Scalar { value })
+                .map(|value| coercion::to_v3(value, &value_type))
                 .collect(),
-        },
+        )?,
         Expression::Exists {
             in_table,
             selection,
-        } => models::Expression::Exists {
-            in_table: match in_table {
-                ExistsInTable::UnrelatedTable { table } => {
-                    Box::new(models::ExistsInTable::Unrelated {
-                        table: table_name(table),
+        } => {
+            // the exists predicate is evaluated against the rows of the target table,
+            // so unqualified columns inside it no longer refer to the root table
+            let (in_table, exists_scope) = match in_table {
+                ExistsInTable::UnrelatedTable { table } => (
+                    models::ExistsInTable::Unrelated {
+                        table: table_name(&table),
                         arguments: HashMap::new(),
-                    })
-                }
+                    },
+                    Scope::nested(table),
+                ),
                 ExistsInTable::RelatedTable { relationship } => {
-                    Box::new(models::ExistsInTable::Related {
-                        relationship: format!("{}.{}", table, relationship),
-                        arguments: HashMap::new(),
-                    })
+                    context
+                        .require_relation_comparisons("Exists expressions over relationships")?;
+                    (
+                        models::ExistsInTable::Related {
+                            relationship: relationship_key(&scope.table, &relationship),
+                            arguments: HashMap::new(),
+                        },
+                        Scope::nested(
+                            context
+                                .relationships
+                                .get(&scope.table, &relationship)?
+                                .target_table
+                                .to_owned(),
+                        ),
+                    )
                 }
-            },
-            predicate: Box::new(map_expression(*selection, table, table_relationships)),
-        },
-    }
+            };
+            models::Expression::Exists {
+                in_table: Box::new(in_table),
+                predicate: Box::new(map_expression(*selection, &exists_scope, context)?),
+            }
+        }
+    })
 }
 
+/// Map a v2 comparison column. In v2, a missing or empty path refers to the current table, and `["$"]` refers to the root table of the query.
 fn map_comparison_column(
     column: ComparisonColumn,
-    table: String,
-    table_relationships: &Vec<TableRelationships>,
-) -> models::ComparisonTarget {
-    match column.path {
-        Some(path) if !path.is_empty() => {
+    scope: &Scope,
+    context: &MappingContext,
+) -> Result<models::ComparisonTarget, ServerError> {
+    let path = column.path.unwrap_or_default();
+
+    match path.first().map(String::as_str) {
+        Some("$") if path.len() == 1 => {
+            Ok(models::ComparisonTarget::RootTableColumn { name: column.name })
+        }
+        Some("$") => Err(ServerError::UncaughtError {
+            details: Some(serde_json::json!({ "column": column.name, "path": path })),
+            message: "Comparison column paths starting at the root table cannot be followed by relationships".to_string(),
+            error_type: ErrorResponseType::UncaughtError,
+        }),
+        None if !scope.nested => {
+            Ok(models::ComparisonTarget::RootTableColumn { name: column.name })
+        }
+        _ => {
+            if !path.is_empty() {
+                context.require_relation_comparisons("Comparisons against columns of related tables")?;
+            }
+
             let mut mapped_path = vec![];
 
-            let mut source_table = table;
+            let mut source_table = scope.table.to_owned();
             for path_segment in path {
-                let target_table =
-                    get_target_table(&source_table, &path_segment, table_relationships);
+                let target_table = context
+                    .relationships
+                    .get(&source_table, &path_segment)?
+                    .target_table
+                    .to_owned();
                 mapped_path.push(models::PathElement {
-                    relationship: format!("{}.{}", source_table, path_segment),
+                    relationship: relationship_key(&source_table, &path_segment),
                     arguments: HashMap::new(),
                 });
                 source_table = target_table;
             }
 
-            models::ComparisonTarget::Column {
+            Ok(models::ComparisonTarget::Column {
                 name: column.name,
                 path: mapped_path,
-            }
+            })
         }
-        _ => models::ComparisonTarget::RootTableColumn { name: column.name },
     }
 }
 
-fn map_response(response: models::QueryResponse, is_foreach: bool) -> QueryResponse {
-    if is_foreach {
-        QueryResponse {
-            aggregates: None,
-            rows: Some(
-                response
-                    .0
-                    .into_iter()
-                    .map(|row| {
-                        let value = row_set_as_json(row);
-
-                        IndexMap::from_iter(
-                            vec![(
-                                "query".to_string(),
-                                Some(RowFieldValue::ColumnFieldValue(value)),
-                            )]
-                            .into_iter(),
-                        )
-                    })
-                    .collect(),
-            ),
-        }
-    } else {
-        let (aggregates, rows) = if let Some(row) = response.0.into_iter().next() {
-            let models::RowSet { rows, aggregates } = row;
-            let aggregates = aggregates.map(|aggregates| {
-                let mut aggregates_map = IndexMap::new();
+/// Whether the target supports predicates over related tables: exists expressions over relationships, and comparisons against columns of related tables.
+/// Exists expressions over unrelated tables are always supported.
+pub fn supports_relation_comparisons(capabilities: &models::CapabilitiesResponse) -> bool {
+    capabilities
+        .capabilities
+        .query
+        .as_ref()
+        .map(|query| query.relation_comparisons.is_some())
+        .unwrap_or(false)
+}
 
-                for (key, value) in aggregates {
-                    aggregates_map.insert(key, value);
-                }
+/// The table that unqualified columns in an expression refer to
+struct Scope {
+    table: TableName,
+    /// Whether the scope is nested inside an exists or relationship predicate, in which case the current table is not the root table
+    nested: bool,
+}
 
-                aggregates_map
-            });
+impl Scope {
+    fn root(table: TableName) -> Self {
+        Self {
+            table,
+            nested: false,
+        }
+    }
+    fn nested(table: TableName) -> Self {
+        Self {
+            table,
+            nested: true,
+        }
+    }
+}
 
-            let rows = rows.map(|rows| {
-                rows.into_iter()
-                    .map(|row| {
-                        let mut row_map = IndexMap::new();
-
-                        for (key, value) in row {
-                            let value = match value {
-                                models::RowFieldValue::Relationship { rows } => {
-                                    row_set_as_json(rows)
-                                }
-                                models::RowFieldValue::Column { value } => value,
-                            };
-                            row_map.insert(key, Some(RowFieldValue::ColumnFieldValue(value)));
-                        }
+/// State shared by the mapping functions for a single request
+struct MappingContext<'a> {
+    relationships: RelationshipLookup<'a>,
+    operators: ComparisonOperators,
+    capabilities: &'a models::CapabilitiesResponse,
+}
 
-                        row_map
-                    })
-                    .collect()
+impl MappingContext<'_> {
+    fn require_relation_comparisons(&self, feature: &str) -> Result<(), ServerError> {
+        if supports_relation_comparisons(self.capabilities) {
+            Ok(())
+        } else {
+            Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({ "capability": "query.relation_comparisons" })),
+                message: format!(
+                    "{feature} require the target connector to support the query.relation_comparisons capability"
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            })
+        }
+    }
+
+    fn require_order_by_aggregate(&self, target_path: &[String]) -> Result<(), ServerError> {
+        if target_path.is_empty() {
+            return Err(ServerError::UncaughtError {
+                details: None,
+                message: "Order by aggregate targets must have a relationship path".to_string(),
+                error_type: ErrorResponseType::UncaughtError,
             });
+        }
+
+        let supports_order_by_aggregate = self
+            .capabilities
+            .capabilities
+            .query
+            .as_ref()
+            .map(|query| query.order_by_aggregate.is_some())
+            .unwrap_or(false);
 
-            (aggregates, rows)
+        if supports_order_by_aggregate {
+            Ok(())
         } else {
-            (None, None)
-        };
-        QueryResponse { aggregates, rows }
+            Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({
+                    "capability": "query.order_by_aggregate",
+                    "target_path": target_path,
+                })),
+                message: "Ordering by aggregates requires the target connector to support the query.order_by_aggregate capability".to_string(),
+                error_type: ErrorResponseType::UncaughtError,
+            })
+        }
     }
 }
 
-fn row_set_as_json(row: models::RowSet) -> serde_json::Value {
+fn row_set_as_json(row: models::RowSet, query: &Query) -> serde_json::Value {
     let mut row_object = serde_json::Map::new();
 
     if let Some(aggregates) = row.aggregates {
         let mut aggregates_object = serde_json::Map::new();
 
         for (key, value) in aggregates {
+            let value = map_aggregate_value(value, &key, query);
             aggregates_object.insert(key, value);
         }
 
@@ -550,13 +990,8 @@ fn row_set_as_json(row: models::RowSet) -> serde_json::Value {
                 let mut row_object = serde_json::Map::new();
 
                 for (key, value) in row {
-                    row_object.insert(
-                        key,
-                        match value {
-                            models::RowFieldValue::Relationship { rows } => row_set_as_json(rows),
-                            models::RowFieldValue::Column { value } => value,
-                        },
-                    );
+                    let value = map_field_value(value, &key, query);
+                    row_object.insert(key, value);
                 }
 
                 serde_json::Value::Object(row_object)
@@ -568,3 +1003,463 @@ fn row_set_as_json(row: models::RowSet) -> serde_json::Value {
 
     serde_json::Value::Object(row_object)
 }
+
+fn map_field_value(value: models::RowFieldValue, key: &str, query: &Query) -> serde_json::Value {
+    let field = query.fields.as_ref().and_then(|fields| fields.get(key));
+
+    match (value, field) {
+        (models::RowFieldValue::Relationship { rows }, Some(Field::Relationship { query, .. })) => {
+            row_set_as_json(rows, query)
+        }
+        (models::RowFieldValue::Column { value }, Some(Field::Column { column_type, .. })) => {
+            coercion::to_v2(value, column_type)
+        }
+        // should not happen, as the response shape follows the request, but don't lose data if it does
+        (models::RowFieldValue::Relationship { rows }, _) => {
+            row_set_as_json(rows, &Query::default())
+        }
+        (models::RowFieldValue::Column { value }, _) => value,
+    }
+}
+
+fn map_aggregate_value(value: serde_json::Value, key: &str, query: &Query) -> serde_json::Value {
+    match query
+        .aggregates
+        .as_ref()
+        .and_then(|aggregates| aggregates.get(key))
+    {
+        Some(Aggregate::SingleColumn { result_type, .. }) => coercion::to_v2(value, result_type),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicUsize, Arc},
+        time::Duration,
+    };
+
+    use axum::{body::Bytes, http::HeaderMap};
+    use ndc_client::models;
+    use serde_json::{json, Value};
+
+    use super::{
+        map_expression, map_order_by, map_query, map_request, merge_split_aggregates,
+        relationships::RelationshipLookup, split_aggregates, MappingContext, Scope, Upstream,
+        UpstreamCache,
+    };
+    use crate::{
+        allowlist::TargetAllowlist,
+        api::query_request::{Expression, Query},
+        cache::{CacheControl, QueryCache},
+        config::Config,
+        error::ServerError,
+        fixtures,
+        operators::ComparisonOperators,
+        upstream::UpstreamClient,
+    };
+
+    fn with_context<T>(query_capabilities: Value, f: impl FnOnce(&MappingContext) -> T) -> T {
+        let schema = fixtures::schema();
+        let capabilities = fixtures::capabilities(json!({
+            "query": query_capabilities,
+            "relationships": {},
+        }));
+        let table_relationships = fixtures::table_relationships();
+        let context = MappingContext {
+            relationships: RelationshipLookup::new(&table_relationships),
+            operators: ComparisonOperators::new(&schema),
+            capabilities: &capabilities,
+        };
+        f(&context)
+    }
+
+    fn map(expression: Value, query_capabilities: Value) -> Result<Value, ServerError> {
+        let expression: Expression = serde_json::from_value(expression).unwrap();
+        let expression = with_context(query_capabilities, |context| {
+            map_expression(
+                expression,
+                &Scope::root(fixtures::table_name("Album")),
+                context,
+            )
+        })?;
+        Ok(serde_json::to_value(expression).unwrap())
+    }
+
+    fn title_is_null() -> Value {
+        json!({
+            "type": "unary_op",
+            "operator": "is_null",
+            "column": { "name": "Title", "column_type": "String" },
+        })
+    }
+
+    fn exists(in_table: Value) -> Value {
+        json!({ "type": "exists", "in_table": in_table, "where": title_is_null() })
+    }
+
+    fn name_is_null_through(path: Value) -> Value {
+        json!({
+            "type": "unary_op",
+            "operator": "is_null",
+            "column": { "name": "Name", "column_type": "String", "path": path },
+        })
+    }
+
+    #[test]
+    fn unrelated_exists_does_not_require_relation_comparisons() {
+        let mapped = map(
+            exists(json!({ "type": "unrelated", "table": ["Album"] })),
+            json!({}),
+        )
+        .unwrap();
+        assert_eq!(mapped["type"], "exists");
+        assert_eq!(mapped["in_table"]["type"], "unrelated");
+        assert_eq!(mapped["in_table"]["table"], "Album");
+    }
+
+    #[test]
+    fn related_exists_requires_relation_comparisons() {
+        let related = || exists(json!({ "type": "related", "relationship": "Artist" }));
+
+        let error = map(related(), json!({})).unwrap_err();
+        let ServerError::UncaughtError { details, .. } = error;
+        assert_eq!(
+            details,
+            Some(json!({ "capability": "query.relation_comparisons" }))
+        );
+
+        let mapped = map(related(), json!({ "relation_comparisons": {} })).unwrap();
+        assert_eq!(mapped["in_table"]["type"], "related");
+        assert_eq!(mapped["in_table"]["relationship"], "Album.Artist");
+    }
+
+    #[test]
+    fn comparison_paths_require_relation_comparisons() {
+        assert!(map(name_is_null_through(json!(["Artist"])), json!({})).is_err());
+
+        let mapped = map(
+            name_is_null_through(json!(["Artist"])),
+            json!({ "relation_comparisons": {} }),
+        )
+        .unwrap();
+        assert_eq!(mapped["column"]["type"], "column");
+        assert_eq!(mapped["column"]["path"][0]["relationship"], "Album.Artist");
+    }
+
+    #[test]
+    fn columns_of_the_current_table_do_not_require_relation_comparisons() {
+        let mapped = map(name_is_null_through(json!([])), json!({})).unwrap();
+        assert_eq!(mapped["column"]["type"], "root_table_column");
+
+        let mapped = map(name_is_null_through(json!(["$"])), json!({})).unwrap();
+        assert_eq!(mapped["column"]["type"], "root_table_column");
+    }
+
+    fn related_exists(relationship: &str, selection: Value) -> Value {
+        json!({
+            "type": "exists",
+            "in_table": { "type": "related", "relationship": relationship },
+            "where": selection,
+        })
+    }
+
+    #[test]
+    fn unqualified_columns_inside_exists_refer_to_the_exists_table() {
+        let mapped = map(
+            related_exists("Artist", name_is_null_through(json!([]))),
+            json!({ "relation_comparisons": {} }),
+        )
+        .unwrap();
+        assert_eq!(mapped["predicate"]["column"]["type"], "column");
+        assert_eq!(mapped["predicate"]["column"]["name"], "Name");
+        assert_eq!(mapped["predicate"]["column"]["path"], json!([]));
+    }
+
+    #[test]
+    fn root_paths_inside_exists_refer_to_the_root_table() {
+        let mapped = map(
+            related_exists("Artist", name_is_null_through(json!(["$"]))),
+            json!({ "relation_comparisons": {} }),
+        )
+        .unwrap();
+        assert_eq!(mapped["predicate"]["column"]["type"], "root_table_column");
+    }
+
+    #[test]
+    fn paths_inside_exists_start_at_the_exists_table() {
+        // Album -> Artist, then Artist -> Albums
+        let mapped = map(
+            related_exists("Artist", name_is_null_through(json!(["Albums"]))),
+            json!({ "relation_comparisons": {} }),
+        )
+        .unwrap();
+        assert_eq!(
+            mapped["predicate"]["column"]["path"][0]["relationship"],
+            "Artist.Albums"
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_comparison_paths() {
+        let capabilities = || json!({ "relation_comparisons": {} });
+        assert!(map(name_is_null_through(json!(["$", "Artist"])), capabilities()).is_err());
+        // Album has no Albums relationship
+        assert!(map(name_is_null_through(json!(["Albums"])), capabilities()).is_err());
+    }
+
+    /// Map an order by on artists, returning the v3 order by elements
+    fn map_artist_order_by(
+        order_by: Value,
+        query_capabilities: Value,
+    ) -> Result<Value, ServerError> {
+        let order_by = serde_json::from_value(order_by).unwrap();
+        let order_by = with_context(query_capabilities, |context| {
+            map_order_by(order_by, &fixtures::table_name("Artist"), context)
+        })?;
+        Ok(serde_json::to_value(order_by).unwrap()["elements"].to_owned())
+    }
+
+    fn order_by_album_count(target_path: Value) -> Value {
+        json!({
+            "elements": [{
+                "order_direction": "desc",
+                "target": { "type": "star_count_aggregate" },
+                "target_path": target_path,
+            }],
+            "relations": {
+                "Albums": { "subrelations": {}, "where": title_is_null() },
+            },
+        })
+    }
+
+    #[test]
+    fn orders_by_aggregates_of_related_tables() {
+        let elements = map_artist_order_by(
+            order_by_album_count(json!(["Albums"])),
+            json!({ "order_by_aggregate": {} }),
+        )
+        .unwrap();
+        let path = &elements[0]["target"]["path"];
+        assert_eq!(path[0]["relationship"], "Artist.Albums");
+        // the predicate of the relation filters the aggregated rows
+        assert!(!path[0]["predicate"].is_null());
+    }
+
+    #[test]
+    fn ordering_by_aggregates_requires_the_capability() {
+        let ServerError::UncaughtError { details, .. } =
+            map_artist_order_by(order_by_album_count(json!(["Albums"])), json!({})).unwrap_err();
+        assert_eq!(details.unwrap()["capability"], "query.order_by_aggregate");
+    }
+
+    #[test]
+    fn refuses_aggregate_order_by_without_a_relationship_path() {
+        let ServerError::UncaughtError { message, .. } = map_artist_order_by(
+            order_by_album_count(json!([])),
+            json!({ "order_by_aggregate": {} }),
+        )
+        .unwrap_err();
+        assert_eq!(
+            message,
+            "Order by aggregate targets must have a relationship path"
+        );
+    }
+
+    #[test]
+    fn refuses_order_by_paths_outside_the_relations() {
+        let order_by = json!({
+            "elements": [{
+                "order_direction": "asc",
+                "target": { "type": "star_count_aggregate" },
+                "target_path": ["Albums"],
+            }],
+            "relations": {},
+        });
+        assert!(map_artist_order_by(order_by, json!({ "order_by_aggregate": {} })).is_err());
+    }
+
+    #[test]
+    fn refuses_ordering_by_columns_across_array_relationships() {
+        let order_by = json!({
+            "elements": [{
+                "order_direction": "asc",
+                "target": { "type": "column", "column": "Title" },
+                "target_path": ["Albums"],
+            }],
+            "relations": { "Albums": { "subrelations": {} } },
+        });
+        let ServerError::UncaughtError { message, .. } =
+            map_artist_order_by(order_by, json!({})).unwrap_err();
+        assert_eq!(
+            message,
+            "Cannot order by column Title across array relationship Artist.Albums"
+        );
+    }
+
+    /// A query for albums with both rows and a count, limited differently
+    fn albums_query(limit: u32, aggregates_limit: u32) -> Value {
+        json!({
+            "fields": { "Title": { "type": "column", "column": "Title", "column_type": "String" } },
+            "aggregates": { "count": { "type": "star_count" } },
+            "limit": limit,
+            "aggregates_limit": aggregates_limit,
+        })
+    }
+
+    fn is_absent(value: &Value) -> bool {
+        value.is_null() || value.as_object().map_or(false, |object| object.is_empty())
+    }
+
+    #[test]
+    fn splits_aggregates_limited_differently_from_rows() {
+        let mut query: Query = serde_json::from_value(albums_query(2, 10)).unwrap();
+        let aggregates_query = split_aggregates(&mut query).unwrap();
+
+        assert!(query.aggregates.is_none());
+        assert_eq!(query.limit, Some(2));
+        assert!(query.fields.is_some());
+
+        assert!(aggregates_query.fields.is_none());
+        assert_eq!(aggregates_query.aggregates_limit, Some(10));
+        assert!(aggregates_query.aggregates.unwrap().contains_key("count"));
+    }
+
+    #[test]
+    fn does_not_split_aggregates_with_the_same_limit() {
+        let mut query: Query = serde_json::from_value(albums_query(10, 10)).unwrap();
+        assert!(split_aggregates(&mut query).is_none());
+        assert!(query.aggregates.is_some());
+    }
+
+    #[test]
+    fn fetches_split_aggregates_of_relationship_fields_with_a_hidden_field() {
+        let query: Query = serde_json::from_value(json!({
+            "fields": {
+                "Albums": {
+                    "type": "relationship",
+                    "relationship": "Albums",
+                    "query": albums_query(2, 10),
+                },
+            },
+        }))
+        .unwrap();
+        let mapped = with_context(json!({}), |context| {
+            map_query(query, fixtures::table_name("Artist"), context)
+        })
+        .unwrap();
+        let mapped = serde_json::to_value(mapped).unwrap();
+
+        let rows_query = &mapped["fields"]["Albums"]["query"];
+        assert_eq!(rows_query["limit"], 2);
+        assert!(is_absent(&rows_query["aggregates"]));
+
+        let aggregates_query = &mapped["fields"]["__split_aggregates_Albums"]["query"];
+        assert_eq!(aggregates_query["limit"], 10);
+        assert!(is_absent(&aggregates_query["fields"]));
+        assert!(!is_absent(&aggregates_query["aggregates"]["count"]));
+    }
+
+    #[test]
+    fn merges_split_aggregates_back_into_relationship_fields() {
+        let query: Query = serde_json::from_value(json!({
+            "fields": {
+                "Albums": {
+                    "type": "relationship",
+                    "relationship": "Albums",
+                    "query": albums_query(2, 10),
+                },
+            },
+        }))
+        .unwrap();
+        let relationship =
+            |rows: Option<Vec<_>>, aggregates: Option<_>| models::RowFieldValue::Relationship {
+                rows: models::RowSet { rows, aggregates },
+            };
+        let row = [
+            ("Albums".to_owned(), relationship(Some(vec![]), None)),
+            (
+                "__split_aggregates_Albums".to_owned(),
+                relationship(
+                    None,
+                    Some([("count".to_owned(), json!(3))].into_iter().collect()),
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let mut row_set = models::RowSet {
+            rows: Some(vec![row]),
+            aggregates: None,
+        };
+
+        merge_split_aggregates(&mut row_set, &query);
+
+        let row = &row_set.rows.unwrap()[0];
+        assert!(row.get("__split_aggregates_Albums").is_none());
+        match row.get("Albums") {
+            Some(models::RowFieldValue::Relationship { rows }) => {
+                assert_eq!(
+                    rows.aggregates
+                        .as_ref()
+                        .and_then(|aggregates| aggregates.get("count")),
+                    Some(&json!(3))
+                );
+                assert_eq!(rows.rows.as_ref().map(Vec::len), Some(0));
+            }
+            _ => panic!("expected the Albums relationship field"),
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_the_combined_size_of_responses() {
+        let config: Config = serde_json::from_value(json!({})).unwrap();
+        let allowlist = Arc::new(TargetAllowlist {
+            schemes: vec!["http".to_owned()],
+            hosts: vec![],
+            networks: vec![],
+        });
+        let client = UpstreamClient::new(Some(&config), &HeaderMap::new(), &allowlist).unwrap();
+        let url = "http://localhost:8100/query";
+        let requests = ["Album", "Artist"].map(|table| {
+            let request = serde_json::from_value(json!({
+                "table": [table],
+                "query": { "fields": {} },
+                "table_relationships": [],
+            }))
+            .unwrap();
+            let capabilities = fixtures::capabilities(json!({ "query": {} }));
+            map_request(request, &fixtures::schema(), &capabilities, &config).unwrap()
+        });
+
+        // serve both responses from the cache, so no target is needed
+        let cache = QueryCache::new(1024);
+        let body: &'static [u8] = br#"[{"rows":[]}]"#;
+        for request in &requests {
+            let key = QueryCache::key(url, client.credentials(), request).unwrap();
+            let ttl = Duration::from_secs(60);
+            cache.insert(key, Bytes::from_static(body), ttl, &CacheControl::default());
+        }
+        let upstream = Upstream {
+            client: &client,
+            url,
+            max_response_size: body.len() * 3 / 2,
+            received: AtomicUsize::new(0),
+            cache: Some(UpstreamCache {
+                cache: &cache,
+                ttl: Duration::from_secs(60),
+                max_entry_size: None,
+                cache_control: CacheControl::default(),
+            }),
+        };
+
+        assert!(upstream.query(&requests[0]).await.is_ok());
+        match upstream.query(&requests[1]).await {
+            Err(ServerError::UncaughtError { message, .. }) => {
+                assert!(message.contains("combined size"), "{message}")
+            }
+            _ => panic!("expected the second response to exceed the combined size"),
+        }
+    }
+}
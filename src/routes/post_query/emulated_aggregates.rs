@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+
+use ndc_client::models;
+
+use crate::{
+    aggregates::{emulated_aggregate_function, EmulatedFunction},
+    api::error_response::ErrorResponseType,
+    coercion::Representation,
+    config::Config,
+    error::ServerError,
+    schema::column_scalar_type,
+};
+
+/// Aggregates of a query that the proxy computes itself, because the target does not support their function.
+/// The aggregated column is fetched as a hidden field instead, and the aggregate is computed from the returned rows.
+#[derive(Default)]
+pub struct AggregatePlan {
+    aggregates: Vec<EmulatedAggregate>,
+    /// Whether the query requested rows. If not, the rows fetched to compute aggregates are dropped.
+    has_fields: bool,
+    max_rows: u32,
+    /// Plans for the queries of relationship fields, by field alias
+    relationships: Vec<(String, AggregatePlan)>,
+}
+
+struct EmulatedAggregate {
+    alias: String,
+    function: EmulatedFunction,
+    /// The representation of the aggregated column, which decides how its values are compared and summed
+    representation: Representation,
+    /// The alias of the hidden field holding the aggregated column
+    field: String,
+}
+
+impl AggregatePlan {
+    /// Rewrite the request so emulated aggregates are fetched as hidden fields, and return the plan to compute them from the response
+    pub fn new(
+        request: &mut models::QueryRequest,
+        schema: &models::SchemaResponse,
+        config: &Config,
+    ) -> Result<Self, ServerError> {
+        plan_query(
+            &mut request.query,
+            &request.table,
+            &request.table_relationships,
+            schema,
+            config,
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        self.aggregates.is_empty() && self.relationships.is_empty()
+    }
+
+    /// Compute the emulated aggregates for a row set returned by the target, and remove the hidden fields
+    pub fn apply(&self, row_set: &mut models::RowSet) -> Result<(), ServerError> {
+        for (field, plan) in &self.relationships {
+            for row in row_set.rows.iter_mut().flatten() {
+                if let Some(models::RowFieldValue::Relationship { rows }) = row.get_mut(field) {
+                    plan.apply(rows)?;
+                }
+            }
+        }
+
+        if self.aggregates.is_empty() {
+            return Ok(());
+        }
+
+        let rows = row_set.rows.take().unwrap_or_default();
+        if rows.len() > self.max_rows as usize {
+            return Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({ "max_rows": self.max_rows })),
+                message: format!(
+                    "Cannot compute emulated aggregates over more than {} rows",
+                    self.max_rows
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            });
+        }
+
+        let aggregates = row_set.aggregates.get_or_insert_with(Default::default);
+        for aggregate in &self.aggregates {
+            let values: Vec<_> = rows
+                .iter()
+                .filter_map(|row| match row.get(&aggregate.field) {
+                    Some(models::RowFieldValue::Column { value }) => Some(value.to_owned()),
+                    _ => None,
+                })
+                .collect();
+            aggregates.insert(
+                aggregate.alias.to_owned(),
+                aggregate
+                    .function
+                    .compute(&values, aggregate.representation),
+            );
+        }
+
+        if self.has_fields {
+            let mut rows = rows;
+            for row in rows.iter_mut() {
+                for aggregate in &self.aggregates {
+                    row.remove(&aggregate.field);
+                }
+            }
+            row_set.rows = Some(rows);
+        }
+
+        Ok(())
+    }
+}
+
+fn plan_query(
+    query: &mut models::Query,
+    table: &str,
+    table_relationships: &HashMap<String, models::Relationship>,
+    schema: &models::SchemaResponse,
+    config: &Config,
+) -> Result<AggregatePlan, ServerError> {
+    let mut plan = AggregatePlan {
+        has_fields: query.fields.is_some(),
+        ..Default::default()
+    };
+
+    for (alias, field) in query.fields.iter_mut().flatten() {
+        if let models::Field::Relationship {
+            query,
+            relationship,
+            ..
+        } = field
+        {
+            if let Some(relationship_info) = table_relationships.get(relationship.as_str()) {
+                let relationship_plan = plan_query(
+                    query,
+                    &relationship_info.target_table,
+                    table_relationships,
+                    schema,
+                    config,
+                )?;
+                if !relationship_plan.is_empty() {
+                    plan.relationships
+                        .push((alias.to_owned(), relationship_plan));
+                }
+            }
+        }
+    }
+
+    let emulate_distinct_counts = config
+        .aggregate_emulation
+        .as_ref()
+        .map_or(false, |aggregate_emulation| {
+            aggregate_emulation.distinct_counts
+        });
+
+    let mut emulated: Vec<(String, String, EmulatedFunction, Representation)> = vec![];
+    for (alias, aggregate) in query.aggregates.iter().flatten() {
+        match aggregate {
+            // functions are only emulated for scalar types whose representation they apply to
+            models::Aggregate::SingleColumn { column, function } => {
+                let emulated_function =
+                    column_scalar_type(schema, table, column).and_then(|scalar_type| {
+                        let (function, _) =
+                            emulated_aggregate_function(schema, scalar_type, function)?;
+                        Some((function, Representation::of(scalar_type)?))
+                    });
+                if let Some((function, representation)) = emulated_function {
+                    emulated.push((
+                        alias.to_owned(),
+                        column.to_owned(),
+                        function,
+                        representation,
+                    ));
+                }
+            }
+            // distinct counts must be emulated once enabled, as the target cannot be relied on for them
+            models::Aggregate::ColumnCount {
+                column,
+                distinct: true,
+            } if emulate_distinct_counts => {
+                let representation = column_scalar_type(schema, table, column)
+                    .and_then(Representation::of)
+                    .filter(|representation| {
+                        EmulatedFunction::CountDistinct.applies_to(*representation)
+                    })
+                    .ok_or_else(|| ServerError::UncaughtError {
+                        details: Some(serde_json::json!({ "aggregate": alias, "column": column })),
+                        message: format!(
+                            "Cannot count distinct values of column {column} in the proxy, as values of its type cannot be compared"
+                        ),
+                        error_type: ErrorResponseType::UncaughtError,
+                    })?;
+                emulated.push((
+                    alias.to_owned(),
+                    column.to_owned(),
+                    EmulatedFunction::CountDistinct,
+                    representation,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    if emulated.is_empty() {
+        return Ok(plan);
+    }
+
+    let max_rows = match &config.aggregate_emulation {
+        Some(aggregate_emulation) => aggregate_emulation.max_rows,
+        None => {
+            return Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({
+                    "aggregates": emulated.iter().map(|(alias, ..)| alias).collect::<Vec<_>>(),
+                })),
+                message: "Aggregate functions not supported by the target require aggregate_emulation to be enabled in the source configuration".to_string(),
+                error_type: ErrorResponseType::UncaughtError,
+            })
+        }
+    };
+
+    let aggregates = query.aggregates.get_or_insert_with(Default::default);
+    let fields = query.fields.get_or_insert_with(Default::default);
+    for (alias, column, function, representation) in emulated {
+        aggregates.remove(&alias);
+        let field = format!("__emulated_aggregate_{alias}");
+        fields.insert(
+            field.to_owned(),
+            models::Field::Column {
+                column,
+                arguments: HashMap::new(),
+            },
+        );
+        plan.aggregates.push(EmulatedAggregate {
+            alias,
+            function,
+            representation,
+            field,
+        });
+    }
+    if query
+        .aggregates
+        .as_ref()
+        .map_or(false, |aggregates| aggregates.is_empty())
+    {
+        query.aggregates = None;
+    }
+
+    // the query's limit and offset bound its aggregates as well as its rows, as mapped from aggregates_limit,
+    // so the fetched rows are exactly those the aggregates cover.
+    // fetch one row more than allowed, so we can tell when the cap is exceeded
+    let row_cap = max_rows.saturating_add(1);
+    query.limit = Some(query.limit.map_or(row_cap, |limit| limit.min(row_cap)));
+    plan.max_rows = max_rows;
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_client::models;
+    use serde_json::{json, Value};
+
+    use super::AggregatePlan;
+    use crate::{config::Config, error::ServerError, fixtures, routes::post_query::map_request};
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "aggregate_emulation": { "max_rows": 100, "distinct_counts": true },
+        }))
+        .unwrap()
+    }
+
+    /// Map an Artist query with the given aggregates, and plan their emulation
+    fn plan(aggregates: Value) -> Result<(models::QueryRequest, AggregatePlan), ServerError> {
+        let request = serde_json::from_value(json!({
+            "table": ["Artist"],
+            "query": { "aggregates": aggregates },
+            "table_relationships": [],
+        }))
+        .unwrap();
+        let capabilities = fixtures::capabilities(json!({ "query": {} }));
+        let mut request = map_request(request, &fixtures::schema(), &capabilities, &config())?;
+        let plan = AggregatePlan::new(&mut request, &fixtures::schema(), &config())?;
+        Ok((request, plan))
+    }
+
+    fn single_column(column: &str, function: &str, result_type: &str) -> Value {
+        json!({
+            "type": "single_column",
+            "column": column,
+            "function": function,
+            "result_type": result_type,
+        })
+    }
+
+    fn distinct_count(column: &str) -> Value {
+        json!({ "type": "column_count", "column": column, "distinct": true })
+    }
+
+    fn column_rows(column: &str, values: Value) -> models::RowSet {
+        models::RowSet {
+            aggregates: None,
+            rows: Some(
+                values
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|value| {
+                        [(
+                            column.to_owned(),
+                            models::RowFieldValue::Column {
+                                value: value.to_owned(),
+                            },
+                        )]
+                        .into_iter()
+                        .collect()
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn emulates_functions_the_target_lacks_for_the_column_type() {
+        let (request, plan) = plan(json!({
+            "max_id": single_column("ArtistId", "max", "Int32"),
+            "avg_id": single_column("ArtistId", "avg", "Float64"),
+        }))
+        .unwrap();
+
+        // Int32 has its own max, but not avg
+        let aggregates = request.query.aggregates.unwrap();
+        assert!(aggregates.contains_key("max_id"));
+        assert!(!aggregates.contains_key("avg_id"));
+
+        let mut row_set = column_rows("__emulated_aggregate_avg_id", json!([1, 2, 6]));
+        plan.apply(&mut row_set).unwrap();
+        assert_eq!(row_set.aggregates.unwrap()["avg_id"], json!(3.0));
+        assert!(row_set.rows.is_none());
+    }
+
+    #[test]
+    fn computes_emulated_functions_in_the_column_representation() {
+        let (_, plan) = plan(json!({
+            "max_name": single_column("Name", "max", "String"),
+            "longest_name": single_column("Name", "longest", "String"),
+        }))
+        .unwrap();
+
+        let names = ["AC/DC", "Aerosmith", "Accept"];
+        let mut row_set = models::RowSet {
+            aggregates: None,
+            rows: Some(
+                names
+                    .into_iter()
+                    .map(|name| {
+                        ["max_name", "longest_name"]
+                            .into_iter()
+                            .map(|alias| {
+                                (
+                                    format!("__emulated_aggregate_{alias}"),
+                                    models::RowFieldValue::Column { value: json!(name) },
+                                )
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            ),
+        };
+        plan.apply(&mut row_set).unwrap();
+
+        let aggregates = row_set.aggregates.unwrap();
+        assert_eq!(aggregates["max_name"], json!("Aerosmith"));
+        assert_eq!(aggregates["longest_name"], json!("Aerosmith"));
+    }
+
+    #[test]
+    fn counts_distinct_values_of_comparable_columns() {
+        let (request, plan) = plan(json!({ "artists": distinct_count("Name") })).unwrap();
+        assert!(request.query.aggregates.is_none());
+
+        let mut row_set = column_rows(
+            "__emulated_aggregate_artists",
+            json!(["AC/DC", "Accept", "AC/DC", null]),
+        );
+        plan.apply(&mut row_set).unwrap();
+        assert_eq!(row_set.aggregates.unwrap()["artists"], json!(2));
+    }
+
+    #[test]
+    fn refuses_to_count_distinct_json_values() {
+        let ServerError::UncaughtError { details, .. } =
+            plan(json!({ "metadata": distinct_count("Metadata") }))
+                .err()
+                .unwrap();
+        assert_eq!(
+            details,
+            Some(json!({ "aggregate": "metadata", "column": "Metadata" }))
+        );
+    }
+}
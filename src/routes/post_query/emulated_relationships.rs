@@ -0,0 +1,981 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use ndc_client::models;
+use serde_json::Value;
+
+use crate::{api::error_response::ErrorResponseType, error::ServerError};
+
+use super::{relationships::parse_relationship_key, Upstream};
+
+type QueryFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<models::QueryResponse, ServerError>> + Send + 'a>>;
+
+/// Where emulated queries are sent: the target, or an in-memory target in tests
+pub trait QueryTarget: Sync {
+    fn query<'a>(&'a self, request: &'a models::QueryRequest) -> QueryFuture<'a>;
+}
+
+impl QueryTarget for Upstream<'_> {
+    fn query<'a>(&'a self, request: &'a models::QueryRequest) -> QueryFuture<'a> {
+        Box::pin(Upstream::query(self, request))
+    }
+}
+
+/// How relationships are emulated, shared by a query and the queries fetching its relationship fields
+pub struct Emulation {
+    supports_foreach: bool,
+    max_per_key_queries: u32,
+    /// Queries fetching related rows one key at a time that can still be sent
+    remaining_per_key_queries: AtomicU32,
+}
+
+impl Emulation {
+    pub fn new(supports_foreach: bool, max_per_key_queries: u32) -> Self {
+        Self {
+            supports_foreach,
+            max_per_key_queries,
+            remaining_per_key_queries: AtomicU32::new(max_per_key_queries),
+        }
+    }
+
+    /// Refuse to send more queries per key than allowed, before sending any of them
+    fn reserve_per_key_queries(&self, count: usize) -> Result<(), ServerError> {
+        let count = u32::try_from(count).unwrap_or(u32::MAX);
+        self.remaining_per_key_queries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                remaining.checked_sub(count)
+            })
+            .map(|_| ())
+            .map_err(|_| {
+                let max_relationship_queries = self.max_per_key_queries;
+                ServerError::UncaughtError {
+                    details: Some(serde_json::json!({
+                        "max_relationship_queries": max_relationship_queries
+                    })),
+                    message: format!(
+                        "Fetching relationship fields would need more than the maximum of {max_relationship_queries} queries"
+                    ),
+                    error_type: ErrorResponseType::UncaughtError,
+                }
+            })
+    }
+}
+
+/// Execute a query against a v3 connector that does not support relationships.
+/// Relationship fields are removed from the query, and fetched with separate queries against the target table,
+/// using the values of the joined columns from the parent rows. The results are then joined back onto the parent rows.
+pub fn execute_query<'a>(
+    upstream: &'a dyn QueryTarget,
+    request: models::QueryRequest,
+    emulation: &'a Emulation,
+) -> QueryFuture<'a> {
+    Box::pin(async move {
+        let mut request = request;
+
+        check_order_by(&request.query)?;
+        if let Some(predicate) = &request.query.predicate {
+            check_predicate(predicate)?;
+        }
+
+        let joins = take_relationship_fields(&mut request.query, &request.table_relationships)?;
+        let table_relationships = std::mem::take(&mut request.table_relationships);
+
+        let mut response = upstream.query(&request).await?;
+
+        for join in joins {
+            let relationship = &table_relationships[&join.relationship];
+
+            let keys = parent_keys(&response, &join);
+            let related_row_sets = fetch_related(
+                upstream,
+                &join,
+                relationship,
+                &table_relationships,
+                keys,
+                emulation,
+            )
+            .await?;
+
+            for row_set in response.0.iter_mut() {
+                for row in row_set.rows.iter_mut().flatten() {
+                    let row_set = row_key(join.join_columns.iter().map(|c| row.get(&c.alias)))
+                        .and_then(|key| related_row_sets.get(&key_string(&key)))
+                        .cloned()
+                        .unwrap_or(models::RowSet {
+                            rows: Some(vec![]),
+                            aggregates: None,
+                        });
+
+                    for join_column in &join.join_columns {
+                        row.remove(&join_column.alias);
+                    }
+                    row.insert(
+                        join.field.to_owned(),
+                        models::RowFieldValue::Relationship { rows: row_set },
+                    );
+                }
+            }
+        }
+
+        Ok(response)
+    })
+}
+
+/// A relationship field removed from a query, to be fetched separately
+struct Join {
+    /// The alias of the relationship field
+    field: String,
+    relationship: String,
+    query: models::Query,
+    join_columns: Vec<JoinColumn>,
+}
+
+struct JoinColumn {
+    /// The alias of the source column, added to the parent query so we can join on it
+    alias: String,
+    target_column: String,
+}
+
+/// Without relationship support, order by targets cannot follow relationships.
+fn check_order_by(query: &models::Query) -> Result<(), ServerError> {
+    for element in query
+        .order_by
+        .iter()
+        .flat_map(|order_by| &order_by.elements)
+    {
+        let relationship = match &element.target {
+            models::OrderByTarget::Column { path, .. } => {
+                path.first().map(|element| &element.relationship)
+            }
+            models::OrderByTarget::SingleColumnAggregate { path, .. }
+            | models::OrderByTarget::StarCountAggregate { path } => {
+                path.first().map(|element| &element.relationship)
+            }
+        };
+
+        if let Some(relationship) = relationship {
+            return Err(unsupported_relationship("order by", relationship));
+        }
+    }
+
+    Ok(())
+}
+
+/// Without relationship support, predicates cannot follow relationships either, whether in exists expressions or comparison column paths.
+fn check_predicate(expression: &models::Expression) -> Result<(), ServerError> {
+    match expression {
+        models::Expression::And { expressions } | models::Expression::Or { expressions } => {
+            expressions.iter().try_for_each(check_predicate)
+        }
+        models::Expression::Not { expression } => check_predicate(expression),
+        models::Expression::UnaryComparisonOperator { column, .. } => {
+            check_comparison_target(column)
+        }
+        models::Expression::BinaryComparisonOperator { column, value, .. } => {
+            check_comparison_target(column)?;
+            check_comparison_value(value)
+        }
+        models::Expression::BinaryArrayComparisonOperator { column, values, .. } => {
+            check_comparison_target(column)?;
+            values.iter().try_for_each(check_comparison_value)
+        }
+        models::Expression::Exists {
+            in_table,
+            predicate,
+        } => match &**in_table {
+            models::ExistsInTable::Related { relationship, .. } => {
+                Err(unsupported_relationship("filter by", relationship))
+            }
+            models::ExistsInTable::Unrelated { .. } => check_predicate(predicate),
+        },
+    }
+}
+
+fn check_comparison_value(value: &models::ComparisonValue) -> Result<(), ServerError> {
+    match value {
+        models::ComparisonValue::Column { column } => check_comparison_target(column),
+        _ => Ok(()),
+    }
+}
+
+fn check_comparison_target(target: &models::ComparisonTarget) -> Result<(), ServerError> {
+    match target {
+        models::ComparisonTarget::Column { path, .. } => match path.first() {
+            Some(element) => Err(unsupported_relationship("filter by", &element.relationship)),
+            None => Ok(()),
+        },
+        models::ComparisonTarget::RootTableColumn { .. } => Ok(()),
+    }
+}
+
+fn unsupported_relationship(operation: &str, relationship: &str) -> ServerError {
+    let (source_table, relationship_name) =
+        parse_relationship_key(relationship).unwrap_or_else(|| (vec![], relationship.to_owned()));
+    ServerError::UncaughtError {
+        details: Some(serde_json::json!({
+            "source_table": source_table,
+            "relationship": relationship_name,
+        })),
+        message: format!(
+            "Cannot {operation} relationship {relationship_name}: the target connector does not support relationships"
+        ),
+        error_type: ErrorResponseType::UncaughtError,
+    }
+}
+
+/// Remove relationship fields from the query, replacing them with the columns needed to join them back
+fn take_relationship_fields(
+    query: &mut models::Query,
+    table_relationships: &HashMap<String, models::Relationship>,
+) -> Result<Vec<Join>, ServerError> {
+    let fields = match query.fields.as_mut() {
+        Some(fields) => fields,
+        None => return Ok(vec![]),
+    };
+
+    let relationship_fields: Vec<String> = fields
+        .iter()
+        .filter(|(_, field)| matches!(field, models::Field::Relationship { .. }))
+        .map(|(alias, _)| alias.to_owned())
+        .collect();
+
+    let mut joins = vec![];
+
+    for field in relationship_fields {
+        if let Some(models::Field::Relationship {
+            query,
+            relationship,
+            ..
+        }) = fields.remove(&field)
+        {
+            let relationship_info = table_relationships.get(&relationship).ok_or_else(|| {
+                ServerError::UncaughtError {
+                    details: Some(serde_json::json!({ "relationship": relationship })),
+                    message: format!(
+                        "Relationship {relationship} not found in table relationships"
+                    ),
+                    error_type: ErrorResponseType::UncaughtError,
+                }
+            })?;
+
+            let join_columns = relationship_info
+                .column_mapping
+                .iter()
+                .map(|(source_column, target_column)| {
+                    let alias = format!("__join_{field}_{source_column}");
+                    fields.insert(
+                        alias.to_owned(),
+                        models::Field::Column {
+                            column: source_column.to_owned(),
+                            arguments: HashMap::new(),
+                        },
+                    );
+                    JoinColumn {
+                        alias,
+                        target_column: target_column.to_owned(),
+                    }
+                })
+                .collect();
+
+            joins.push(Join {
+                field,
+                relationship,
+                query: *query,
+                join_columns,
+            });
+        }
+    }
+
+    Ok(joins)
+}
+
+/// The distinct values of the join columns across all parent rows
+fn parent_keys(response: &models::QueryResponse, join: &Join) -> Vec<Vec<Value>> {
+    let mut seen = std::collections::HashSet::new();
+    let mut keys = vec![];
+
+    for row in response
+        .0
+        .iter()
+        .flat_map(|row_set| row_set.rows.iter().flatten())
+    {
+        if let Some(key) = row_key(join.join_columns.iter().map(|c| row.get(&c.alias))) {
+            if seen.insert(key_string(&key)) {
+                keys.push(key);
+            }
+        }
+    }
+
+    keys
+}
+
+/// Fetch the related rows for each key, returning a map from the key to the related rows.
+async fn fetch_related(
+    upstream: &dyn QueryTarget,
+    join: &Join,
+    relationship: &models::Relationship,
+    table_relationships: &HashMap<String, models::Relationship>,
+    keys: Vec<Vec<Value>>,
+    emulation: &Emulation,
+) -> Result<HashMap<String, models::RowSet>, ServerError> {
+    let mut related_row_sets = HashMap::new();
+
+    if keys.is_empty() {
+        return Ok(related_row_sets);
+    }
+
+    // the session arguments set on the relationship become the arguments of the related collection
+    let arguments: HashMap<_, _> = relationship
+        .arguments
+        .iter()
+        .filter_map(|(argument, value)| match value {
+            models::RelationshipArgument::Literal { value } => Some((
+                argument.to_owned(),
+                models::Argument::Literal {
+                    value: value.to_owned(),
+                },
+            )),
+            _ => None,
+        })
+        .collect();
+    let related_request = |query: models::Query, variables| models::QueryRequest {
+        table: relationship.target_table.to_owned(),
+        arguments: arguments.to_owned(),
+        variables,
+        query,
+        table_relationships: table_relationships.to_owned(),
+    };
+
+    let query = &join.query;
+
+    if emulation.supports_foreach {
+        // one row set per key, filtered using variables
+        let mut query = query.to_owned();
+        query.predicate = Some(and(
+            query.predicate.take(),
+            join.join_columns
+                .iter()
+                .enumerate()
+                .map(|(index, join_column)| {
+                    column_equals(
+                        &join_column.target_column,
+                        models::ComparisonValue::Variable {
+                            name: variable_name(index),
+                        },
+                    )
+                })
+                .collect(),
+        ));
+        let variables = keys
+            .iter()
+            .map(|key| {
+                key.iter()
+                    .enumerate()
+                    .map(|(index, value)| (variable_name(index), value.to_owned()))
+                    .collect()
+            })
+            .collect();
+
+        let response =
+            execute_query(upstream, related_request(query, Some(variables)), emulation).await?;
+
+        for (key, row_set) in keys.iter().zip(response.0) {
+            related_row_sets.insert(key_string(key), row_set);
+        }
+    } else if query.limit.is_none()
+        && query.offset.is_none()
+        && query
+            .aggregates
+            .as_ref()
+            .map_or(true, |aggregates| aggregates.is_empty())
+    {
+        // without per key limits or aggregates, all related rows can be fetched in one query and grouped by key
+        let mut query = query.to_owned();
+        query.predicate = Some(and(query.predicate.take(), vec![keys_in(join, &keys)]));
+        let fields = query.fields.get_or_insert_with(HashMap::new);
+        for join_column in &join.join_columns {
+            fields.insert(
+                join_column.alias.to_owned(),
+                models::Field::Column {
+                    column: join_column.target_column.to_owned(),
+                    arguments: HashMap::new(),
+                },
+            );
+        }
+
+        let response = execute_query(upstream, related_request(query, None), emulation).await?;
+
+        for row in response
+            .0
+            .into_iter()
+            .filter_map(|row_set| row_set.rows)
+            .flatten()
+        {
+            if let Some(key) = row_key(join.join_columns.iter().map(|c| row.get(&c.alias))) {
+                let key = key_string(&key);
+                let mut row = row;
+                for join_column in &join.join_columns {
+                    row.remove(&join_column.alias);
+                }
+                let row_set = related_row_sets.entry(key).or_insert(models::RowSet {
+                    rows: Some(vec![]),
+                    aggregates: None,
+                });
+                row_set.rows.get_or_insert_with(Vec::new).push(row);
+            }
+        }
+    } else {
+        // limits, offsets and aggregates apply per key, so we need one query per key
+        emulation.reserve_per_key_queries(keys.len())?;
+        for key in keys {
+            let mut query = query.to_owned();
+            query.predicate = Some(and(query.predicate.take(), key_equals(join, &key)));
+
+            let response = execute_query(upstream, related_request(query, None), emulation).await?;
+
+            if let Some(row_set) = response.0.into_iter().next() {
+                related_row_sets.insert(key_string(&key), row_set);
+            }
+        }
+    }
+
+    Ok(related_row_sets)
+}
+
+/// Match any of the keys. Single column joins use `in`, composite joins fall back to a disjunction of equalities.
+fn keys_in(join: &Join, keys: &[Vec<Value>]) -> models::Expression {
+    match join.join_columns.as_slice() {
+        [join_column] => models::Expression::BinaryArrayComparisonOperator {
+            column: Box::new(models::ComparisonTarget::RootTableColumn {
+                name: join_column.target_column.to_owned(),
+            }),
+            operator: Box::new(models::BinaryArrayComparisonOperator::In),
+            values: keys
+                .iter()
+                .flatten()
+                .map(|value| models::ComparisonValue::Scalar {
+                    value: value.to_owned(),
+                })
+                .collect(),
+        },
+        _ => models::Expression::Or {
+            expressions: keys
+                .iter()
+                .map(|key| and(None, key_equals(join, key)))
+                .collect(),
+        },
+    }
+}
+
+fn key_equals(join: &Join, key: &[Value]) -> Vec<models::Expression> {
+    join.join_columns
+        .iter()
+        .zip(key)
+        .map(|(join_column, value)| {
+            column_equals(
+                &join_column.target_column,
+                models::ComparisonValue::Scalar {
+                    value: value.to_owned(),
+                },
+            )
+        })
+        .collect()
+}
+
+fn column_equals(column: &str, value: models::ComparisonValue) -> models::Expression {
+    models::Expression::BinaryComparisonOperator {
+        column: Box::new(models::ComparisonTarget::RootTableColumn {
+            name: column.to_owned(),
+        }),
+        operator: Box::new(models::BinaryComparisonOperator::Equal),
+        value: Box::new(value),
+    }
+}
+
+fn and(
+    predicate: Option<models::Expression>,
+    expressions: Vec<models::Expression>,
+) -> models::Expression {
+    models::Expression::And {
+        expressions: predicate.into_iter().chain(expressions).collect(),
+    }
+}
+
+fn variable_name(index: usize) -> String {
+    format!("__join_{index}")
+}
+
+/// The key of a row, from the values of its join columns. Rows with null join columns have no key, and join with nothing.
+fn row_key<'a>(
+    values: impl Iterator<Item = Option<&'a models::RowFieldValue>>,
+) -> Option<Vec<Value>> {
+    values
+        .map(|value| match value {
+            Some(models::RowFieldValue::Column { value }) if !value.is_null() => {
+                Some(value.to_owned())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn key_string(key: &[Value]) -> String {
+    serde_json::to_string(key).expect("json values should serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use ndc_client::models;
+    use serde_json::{json, Value};
+
+    use super::{execute_query, take_relationship_fields, Emulation, QueryFuture, QueryTarget};
+    use crate::error::ServerError;
+    use crate::{config::Config, fixtures, routes::post_query::map_request};
+
+    /// An in-memory target without relationship support, recording the requests it receives
+    struct FakeTarget {
+        tables: HashMap<&'static str, Vec<Value>>,
+        requests: Mutex<Vec<models::QueryRequest>>,
+    }
+
+    impl QueryTarget for FakeTarget {
+        fn query<'a>(&'a self, request: &'a models::QueryRequest) -> QueryFuture<'a> {
+            self.requests.lock().unwrap().push(request.clone());
+            let response = self.evaluate(request);
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    type Variables<'a> = dyn Fn(&str) -> Option<Value> + 'a;
+
+    impl FakeTarget {
+        fn new() -> Self {
+            let artist = |id: u32, name: &str| json!({ "ArtistId": id, "Name": name });
+            let album = |id: u32, title: &str, artist_id: u32| json!({ "AlbumId": id, "Title": title, "ArtistId": artist_id });
+            Self {
+                tables: HashMap::from([
+                    (
+                        "Artist",
+                        vec![
+                            artist(1, "AC/DC"),
+                            artist(2, "Accept"),
+                            artist(3, "Aerosmith"),
+                        ],
+                    ),
+                    (
+                        "Album",
+                        vec![
+                            album(1, "For Those About To Rock", 1),
+                            album(2, "Balls to the Wall", 2),
+                            album(3, "Restless and Wild", 2),
+                            album(4, "Let There Be Rock", 1),
+                        ],
+                    ),
+                ]),
+                requests: Mutex::new(vec![]),
+            }
+        }
+
+        fn requests(&self) -> Vec<models::QueryRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn evaluate(&self, request: &models::QueryRequest) -> models::QueryResponse {
+            let rows = &self.tables[request.table.as_str()];
+            let row_sets = match &request.variables {
+                Some(variable_sets) => variable_sets
+                    .iter()
+                    .map(|variables| {
+                        self.row_set(rows, &request.query, &|name| variables.get(name).cloned())
+                    })
+                    .collect(),
+                None => vec![self.row_set(rows, &request.query, &|_| None)],
+            };
+            models::QueryResponse(row_sets)
+        }
+
+        fn row_set(
+            &self,
+            rows: &[Value],
+            query: &models::Query,
+            variables: &Variables,
+        ) -> models::RowSet {
+            let selected: Vec<&Value> = rows
+                .iter()
+                .filter(|row| {
+                    query
+                        .predicate
+                        .as_ref()
+                        .map_or(true, |predicate| matches(row, predicate, variables))
+                })
+                .skip(query.offset.unwrap_or(0) as usize)
+                .take(query.limit.map_or(usize::MAX, |limit| limit as usize))
+                .collect();
+
+            models::RowSet {
+                aggregates: query.aggregates.as_ref().map(|aggregates| {
+                    aggregates
+                        .iter()
+                        .map(|(alias, aggregate)| match aggregate {
+                            models::Aggregate::StarCount {} => {
+                                (alias.to_owned(), json!(selected.len()))
+                            }
+                            _ => panic!("only star counts are supported"),
+                        })
+                        .collect()
+                }),
+                rows: query.fields.as_ref().map(|fields| {
+                    selected
+                        .iter()
+                        .map(|row| {
+                            fields
+                                .iter()
+                                .map(|(alias, field)| match field {
+                                    models::Field::Column { column, .. } => (
+                                        alias.to_owned(),
+                                        models::RowFieldValue::Column {
+                                            value: row[column.as_str()].clone(),
+                                        },
+                                    ),
+                                    models::Field::Relationship { .. } => {
+                                        panic!("relationship fields should be emulated")
+                                    }
+                                })
+                                .collect()
+                        })
+                        .collect()
+                }),
+            }
+        }
+    }
+
+    fn matches(row: &Value, expression: &models::Expression, variables: &Variables) -> bool {
+        match expression {
+            models::Expression::And { expressions } => expressions
+                .iter()
+                .all(|expression| matches(row, expression, variables)),
+            models::Expression::Or { expressions } => expressions
+                .iter()
+                .any(|expression| matches(row, expression, variables)),
+            models::Expression::Not { expression } => !matches(row, expression, variables),
+            models::Expression::BinaryComparisonOperator {
+                column,
+                operator,
+                value,
+            } => {
+                assert!(matches!(
+                    **operator,
+                    models::BinaryComparisonOperator::Equal
+                ));
+                column_value(row, column) == comparison_value(row, value, variables)
+            }
+            models::Expression::BinaryArrayComparisonOperator { column, values, .. } => values
+                .iter()
+                .any(|value| column_value(row, column) == comparison_value(row, value, variables)),
+            _ => panic!("unsupported expression"),
+        }
+    }
+
+    fn column_value(row: &Value, target: &models::ComparisonTarget) -> Value {
+        match target {
+            models::ComparisonTarget::RootTableColumn { name } => row[name.as_str()].clone(),
+            models::ComparisonTarget::Column { name, path } if path.is_empty() => {
+                row[name.as_str()].clone()
+            }
+            models::ComparisonTarget::Column { .. } => panic!("paths should be emulated"),
+        }
+    }
+
+    fn comparison_value(
+        row: &Value,
+        value: &models::ComparisonValue,
+        variables: &Variables,
+    ) -> Value {
+        match value {
+            models::ComparisonValue::Scalar { value } => value.clone(),
+            models::ComparisonValue::Column { column } => column_value(row, column),
+            models::ComparisonValue::Variable { name } => {
+                variables(name).expect("variables should be set")
+            }
+        }
+    }
+
+    /// A v2 request mapped to v3, for a target without relationship support
+    fn request(table: &str, query: Value) -> models::QueryRequest {
+        let request = serde_json::from_value(json!({
+            "table": [table],
+            "query": query,
+            "table_relationships": fixtures::table_relationships(),
+        }))
+        .unwrap();
+        let capabilities = fixtures::capabilities(json!({ "query": {} }));
+        let config: Config = serde_json::from_value(json!({})).unwrap();
+        map_request(request, &fixtures::schema(), &capabilities, &config).unwrap()
+    }
+
+    fn column(column: &str) -> Value {
+        json!({ "type": "column", "column": column, "column_type": "String" })
+    }
+
+    fn relationship(relationship: &str, query: Value) -> Value {
+        json!({ "type": "relationship", "relationship": relationship, "query": query })
+    }
+
+    /// The rows of a row set, with relationship fields as arrays of their rows
+    fn rows(row_set: &models::RowSet) -> Value {
+        Value::Array(
+            row_set
+                .rows
+                .iter()
+                .flatten()
+                .map(|row| {
+                    Value::Object(
+                        row.iter()
+                            .map(|(alias, value)| {
+                                let value = match value {
+                                    models::RowFieldValue::Column { value } => value.clone(),
+                                    models::RowFieldValue::Relationship { rows: row_set } => {
+                                        rows(row_set)
+                                    }
+                                };
+                                (alias.to_owned(), value)
+                            })
+                            .collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    async fn execute(
+        target: &FakeTarget,
+        request: models::QueryRequest,
+        supports_foreach: bool,
+    ) -> Value {
+        let emulation = Emulation::new(supports_foreach, 100);
+        let response = execute_query(target, request, &emulation).await.unwrap();
+        assert_eq!(response.0.len(), 1);
+        rows(&response.0[0])
+    }
+
+    #[test]
+    fn replaces_relationship_fields_with_aliased_join_columns() {
+        let mut request = request(
+            "Album",
+            json!({
+                "fields": {
+                    "Title": column("Title"),
+                    "Artist": relationship("Artist", json!({ "fields": { "Name": column("Name") } })),
+                },
+            }),
+        );
+
+        let joins =
+            take_relationship_fields(&mut request.query, &request.table_relationships).unwrap();
+
+        assert_eq!(joins.len(), 1);
+        let join = &joins[0];
+        assert_eq!(join.field, "Artist");
+        assert_eq!(join.relationship, "Album.Artist");
+        assert_eq!(join.join_columns.len(), 1);
+        assert_eq!(join.join_columns[0].alias, "__join_Artist_ArtistId");
+        assert_eq!(join.join_columns[0].target_column, "ArtistId");
+
+        let fields = request.query.fields.unwrap();
+        assert!(fields.get("Artist").is_none());
+        assert!(fields.get("Title").is_some());
+        match fields.get("__join_Artist_ArtistId") {
+            Some(models::Field::Column { column, .. }) => assert_eq!(column, "ArtistId"),
+            _ => panic!("expected the join column"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_object_relationships_in_one_batched_query() {
+        let target = FakeTarget::new();
+        let request = request(
+            "Album",
+            json!({
+                "fields": {
+                    "Title": column("Title"),
+                    "Artist": relationship("Artist", json!({ "fields": { "Name": column("Name") } })),
+                },
+            }),
+        );
+
+        let rows = execute(&target, request, false).await;
+
+        assert_eq!(
+            rows,
+            json!([
+                { "Title": "For Those About To Rock", "Artist": [{ "Name": "AC/DC" }] },
+                { "Title": "Balls to the Wall", "Artist": [{ "Name": "Accept" }] },
+                { "Title": "Restless and Wild", "Artist": [{ "Name": "Accept" }] },
+                { "Title": "Let There Be Rock", "Artist": [{ "Name": "AC/DC" }] },
+            ])
+        );
+        let requests = target.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].variables.is_none());
+    }
+
+    #[tokio::test]
+    async fn fetches_array_relationships_with_foreach_variables() {
+        let target = FakeTarget::new();
+        let request = request(
+            "Artist",
+            json!({
+                "fields": {
+                    "Name": column("Name"),
+                    "Albums": relationship("Albums", json!({ "fields": { "Title": column("Title") } })),
+                },
+            }),
+        );
+
+        let rows = execute(&target, request, true).await;
+
+        assert_eq!(
+            rows,
+            json!([
+                {
+                    "Name": "AC/DC",
+                    "Albums": [{ "Title": "For Those About To Rock" }, { "Title": "Let There Be Rock" }],
+                },
+                {
+                    "Name": "Accept",
+                    "Albums": [{ "Title": "Balls to the Wall" }, { "Title": "Restless and Wild" }],
+                },
+                { "Name": "Aerosmith", "Albums": [] },
+            ])
+        );
+        let requests = target.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].variables.as_ref().map(Vec::len), Some(3));
+    }
+
+    #[tokio::test]
+    async fn fetches_limited_relationships_one_key_at_a_time() {
+        let target = FakeTarget::new();
+        let request = request(
+            "Artist",
+            json!({
+                "fields": {
+                    "Name": column("Name"),
+                    "Albums": relationship(
+                        "Albums",
+                        json!({ "fields": { "Title": column("Title") }, "limit": 1 }),
+                    ),
+                },
+            }),
+        );
+
+        let rows = execute(&target, request, false).await;
+
+        assert_eq!(
+            rows,
+            json!([
+                { "Name": "AC/DC", "Albums": [{ "Title": "For Those About To Rock" }] },
+                { "Name": "Accept", "Albums": [{ "Title": "Balls to the Wall" }] },
+                { "Name": "Aerosmith", "Albums": [] },
+            ])
+        );
+        // one query for the artists, and one for the albums of each artist
+        assert_eq!(target.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn fetches_nested_relationships() {
+        let target = FakeTarget::new();
+        let request = request(
+            "Artist",
+            json!({
+                "fields": {
+                    "Albums": relationship(
+                        "Albums",
+                        json!({
+                            "fields": {
+                                "Title": column("Title"),
+                                "Artist": relationship(
+                                    "Artist",
+                                    json!({ "fields": { "Name": column("Name") } }),
+                                ),
+                            },
+                            "where": {
+                                "type": "binary_op",
+                                "operator": "equal",
+                                "column": { "name": "AlbumId", "column_type": "Int32" },
+                                "value": { "type": "scalar", "value": 3, "value_type": "Int32" },
+                            },
+                        }),
+                    ),
+                },
+            }),
+        );
+
+        let rows = execute(&target, request, false).await;
+
+        assert_eq!(
+            rows,
+            json!([
+                { "Albums": [] },
+                { "Albums": [{ "Title": "Restless and Wild", "Artist": [{ "Name": "Accept" }] }] },
+                { "Albums": [] },
+            ])
+        );
+        assert_eq!(target.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn batches_relationships_with_empty_aggregates() {
+        let target = FakeTarget::new();
+        let request = request(
+            "Album",
+            json!({
+                "fields": {
+                    "Artist": relationship(
+                        "Artist",
+                        json!({ "fields": { "Name": column("Name") }, "aggregates": {} }),
+                    ),
+                },
+            }),
+        );
+
+        let rows = execute(&target, request, false).await;
+
+        assert_eq!(rows[1], json!({ "Artist": [{ "Name": "Accept" }] }));
+        assert_eq!(target.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_more_queries_per_key_than_allowed() {
+        let target = FakeTarget::new();
+        let request = request(
+            "Artist",
+            json!({
+                "fields": {
+                    "Albums": relationship(
+                        "Albums",
+                        json!({ "fields": { "Title": column("Title") }, "limit": 1 }),
+                    ),
+                },
+            }),
+        );
+
+        let emulation = Emulation::new(false, 2);
+        match execute_query(&target, request, &emulation).await {
+            Err(ServerError::UncaughtError { details, .. }) => {
+                assert_eq!(details, Some(json!({ "max_relationship_queries": 2 })))
+            }
+            _ => panic!("expected the per key queries to be refused"),
+        }
+        // none of the per key queries were sent
+        assert_eq!(target.requests().len(), 1);
+    }
+}
@@ -0,0 +1,80 @@
+use ndc_client::models;
+
+use crate::api::query_request::Query;
+
+use super::{map_aggregate_value, map_field_value, row_set_as_json};
+
+/// Encode a v3 response as the body of a v2 response.
+/// Rows are mapped as they are written, so no v2 copy of the response is built alongside the v3 one,
+/// but the whole response is in memory, bounded by the maximum response size.
+pub fn encode_response(
+    response: models::QueryResponse,
+    query: &Query,
+    is_foreach: bool,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_response(&mut body, response, query, is_foreach);
+    body
+}
+
+fn write_response(
+    body: &mut Vec<u8>,
+    response: models::QueryResponse,
+    query: &Query,
+    is_foreach: bool,
+) {
+    if is_foreach {
+        // each foreach row is an object with the row set of the query for that row under the "query" key
+        body.extend_from_slice(b"{\"rows\":[");
+        for (index, row_set) in response.0.into_iter().enumerate() {
+            if index > 0 {
+                body.push(b',');
+            }
+            write_json(
+                body,
+                &serde_json::json!({ "query": row_set_as_json(row_set, query) }),
+            );
+        }
+        body.extend_from_slice(b"]}");
+    } else {
+        body.push(b'{');
+        if let Some(models::RowSet { rows, aggregates }) = response.0.into_iter().next() {
+            if let Some(aggregates) = aggregates {
+                let aggregates: serde_json::Map<_, _> = aggregates
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = map_aggregate_value(value, &key, query);
+                        (key, value)
+                    })
+                    .collect();
+                body.extend_from_slice(b"\"aggregates\":");
+                write_json(body, &serde_json::Value::Object(aggregates));
+                if rows.is_some() {
+                    body.push(b',');
+                }
+            }
+            if let Some(rows) = rows {
+                body.extend_from_slice(b"\"rows\":[");
+                for (index, row) in rows.into_iter().enumerate() {
+                    if index > 0 {
+                        body.push(b',');
+                    }
+                    let row: serde_json::Map<_, _> = row
+                        .into_iter()
+                        .map(|(key, value)| {
+                            let value = map_field_value(value, &key, query);
+                            (key, value)
+                        })
+                        .collect();
+                    write_json(body, &serde_json::Value::Object(row));
+                }
+                body.push(b']');
+            }
+        }
+        body.push(b'}');
+    }
+}
+
+fn write_json(body: &mut Vec<u8>, value: &serde_json::Value) {
+    serde_json::to_writer(body, value).expect("json values should serialize");
+}
@@ -0,0 +1,196 @@
+use ndc_client::models;
+
+use crate::{api::error_response::ErrorResponseType, config::QueryLimits, error::ServerError};
+
+/// Enforce the nesting depth limit, and make the target return at most one row more than the row limit for each query,
+/// so results over the limit can be detected and refused rather than silently truncated.
+/// When relationships are emulated, relationship fields are left uncapped, as a limit would force one query per parent row
+/// rather than one query for all of them. Their rows are checked against the limit once grouped by parent row instead.
+pub fn limit_query(
+    query: &mut models::Query,
+    limits: &QueryLimits,
+    emulated_relationships: bool,
+) -> Result<(), ServerError> {
+    limit_query_at_depth(query, limits, emulated_relationships, 0)
+}
+
+fn limit_query_at_depth(
+    query: &mut models::Query,
+    limits: &QueryLimits,
+    emulated_relationships: bool,
+    depth: u32,
+) -> Result<(), ServerError> {
+    if let Some(max_depth) = limits.max_depth {
+        if depth > max_depth {
+            return Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({ "max_depth": max_depth })),
+                message: format!(
+                    "Query nests relationship fields more than the maximum depth of {max_depth}"
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            });
+        }
+    }
+
+    // queries without fields return no rows, and limiting them would change their aggregates
+    let capped = query.fields.is_some() && (depth == 0 || !emulated_relationships);
+    if let Some(max_rows) = limits.max_rows.filter(|_| capped) {
+        let row_cap = max_rows.saturating_add(1);
+        query.limit = Some(query.limit.map_or(row_cap, |limit| limit.min(row_cap)));
+    }
+
+    for field in query
+        .fields
+        .iter_mut()
+        .flat_map(|fields| fields.values_mut())
+    {
+        if let models::Field::Relationship { query, .. } = field {
+            limit_query_at_depth(query, limits, emulated_relationships, depth + 1)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuse row sets, including those of relationship fields, with more rows than the limit
+pub fn check_rows(row_set: &models::RowSet, max_rows: u32) -> Result<(), ServerError> {
+    if let Some(rows) = &row_set.rows {
+        if rows.len() > max_rows as usize {
+            return Err(ServerError::UncaughtError {
+                details: Some(serde_json::json!({ "max_rows": max_rows })),
+                message: format!("Query returned more than the maximum of {max_rows} rows"),
+                error_type: ErrorResponseType::UncaughtError,
+            });
+        }
+
+        for value in rows.iter().flat_map(|row| row.values()) {
+            if let models::RowFieldValue::Relationship { rows } = value {
+                check_rows(rows, max_rows)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_client::models;
+    use serde_json::json;
+
+    use super::{check_rows, limit_query};
+    use crate::{config::QueryLimits, error::ServerError};
+
+    fn limits(limits: serde_json::Value) -> QueryLimits {
+        serde_json::from_value(limits).unwrap()
+    }
+
+    fn query(limit: Option<u32>, relationship_query: Option<models::Query>) -> models::Query {
+        models::Query {
+            aggregates: None,
+            fields: Some(
+                relationship_query
+                    .into_iter()
+                    .map(|query| {
+                        (
+                            "Albums".to_owned(),
+                            models::Field::Relationship {
+                                query: Box::new(query),
+                                relationship: "Artist.Albums".to_owned(),
+                                arguments: Default::default(),
+                            },
+                        )
+                    })
+                    .collect(),
+            ),
+            limit,
+            offset: None,
+            order_by: None,
+            predicate: None,
+        }
+    }
+
+    fn relationship_limit(query: &models::Query) -> Option<u32> {
+        match query
+            .fields
+            .as_ref()
+            .and_then(|fields| fields.get("Albums"))
+        {
+            Some(models::Field::Relationship { query, .. }) => query.limit,
+            _ => panic!("expected the Albums relationship field"),
+        }
+    }
+
+    fn rows(count: usize, nested: Option<models::RowSet>) -> models::RowSet {
+        models::RowSet {
+            aggregates: None,
+            rows: Some(
+                (0..count)
+                    .map(|_| {
+                        nested
+                            .iter()
+                            .map(|rows| {
+                                (
+                                    "Albums".to_owned(),
+                                    models::RowFieldValue::Relationship {
+                                        rows: rows.to_owned(),
+                                    },
+                                )
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn fetches_one_row_more_than_the_limit() {
+        let limits = limits(json!({ "max_rows": 10 }));
+
+        let mut unlimited = query(None, Some(query(None, None)));
+        limit_query(&mut unlimited, &limits, false).unwrap();
+        assert_eq!(unlimited.limit, Some(11));
+        assert_eq!(relationship_limit(&unlimited), Some(11));
+
+        let mut limited = query(Some(5), None);
+        limit_query(&mut limited, &limits, false).unwrap();
+        assert_eq!(limited.limit, Some(5));
+    }
+
+    #[test]
+    fn leaves_emulated_relationship_fields_uncapped() {
+        let mut query = query(None, Some(query(None, None)));
+        limit_query(&mut query, &limits(json!({ "max_rows": 10 })), true).unwrap();
+        assert_eq!(query.limit, Some(11));
+        assert_eq!(relationship_limit(&query), None);
+    }
+
+    #[test]
+    fn does_not_limit_queries_without_fields() {
+        let mut query = query(None, None);
+        query.fields = None;
+        limit_query(&mut query, &limits(json!({ "max_rows": 10 })), false).unwrap();
+        assert_eq!(query.limit, None);
+    }
+
+    #[test]
+    fn refuses_queries_nested_deeper_than_the_limit() {
+        let limits = limits(json!({ "max_depth": 1 }));
+        let nested = || query(None, Some(query(None, Some(query(None, None)))));
+
+        let ServerError::UncaughtError { details, .. } =
+            limit_query(&mut nested(), &limits, false).unwrap_err();
+        assert_eq!(details, Some(json!({ "max_depth": 1 })));
+
+        assert!(limit_query(&mut query(None, Some(query(None, None))), &limits, false).is_ok());
+    }
+
+    #[test]
+    fn refuses_row_sets_over_the_limit_at_any_depth() {
+        assert!(check_rows(&rows(2, None), 2).is_ok());
+        assert!(check_rows(&rows(3, None), 2).is_err());
+        assert!(check_rows(&rows(1, Some(rows(2, None))), 2).is_ok());
+        assert!(check_rows(&rows(1, Some(rows(3, None))), 2).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use indexmap::IndexMap;
+
+use crate::{
+    api::{
+        error_response::ErrorResponseType,
+        query_request::{Relationship, TableName, TableRelationships},
+    },
+    error::ServerError,
+};
+
+/// Lookup table for the relationships in a v2 request, by source table and relationship name
+pub struct RelationshipLookup<'a> {
+    tables: HashMap<&'a TableName, &'a IndexMap<String, Relationship>>,
+}
+
+impl<'a> RelationshipLookup<'a> {
+    pub fn new(table_relationships: &'a [TableRelationships]) -> Self {
+        Self {
+            tables: table_relationships
+                .iter()
+                .map(|table_relationships| {
+                    (
+                        &table_relationships.source_table,
+                        &table_relationships.relationships,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        source_table: &TableName,
+        relationship: &str,
+    ) -> Result<&'a Relationship, ServerError> {
+        self.tables
+            .get(source_table)
+            .and_then(|relationships| relationships.get(relationship))
+            .ok_or_else(|| ServerError::UncaughtError {
+                details: Some(serde_json::json!({
+                    "source_table": source_table,
+                    "relationship": relationship,
+                })),
+                message: format!(
+                    "Relationship {relationship} on table {} not found in table relationships",
+                    source_table.join(".")
+                ),
+                error_type: ErrorResponseType::UncaughtError,
+            })
+    }
+}
+
+/// The name of a relationship in the v3 request.
+/// v2 relationship names are only unique per source table, so the key is made of the source table name segments and the relationship name.
+/// Each component is escaped, so dots in table or relationship names can't cause two keys to collide, and the key can be parsed back.
+pub fn relationship_key(source_table: &TableName, relationship: &str) -> String {
+    source_table
+        .iter()
+        .map(String::as_str)
+        .chain(std::iter::once(relationship))
+        .map(|component| component.replace('\\', "\\\\").replace('.', "\\."))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Parse a key created by `relationship_key` back into the source table and relationship name
+pub fn parse_relationship_key(key: &str) -> Option<(TableName, String)> {
+    let mut components = vec![];
+    let mut component = String::new();
+    let mut chars = key.chars();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => component.push(chars.next()?),
+            '.' => components.push(std::mem::take(&mut component)),
+            _ => component.push(char),
+        }
+    }
+    components.push(component);
+
+    let relationship = components.pop()?;
+
+    if components.is_empty() {
+        None
+    } else {
+        Some((components, relationship))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_relationship_key, relationship_key};
+
+    fn round_trip(source_table: &[&str], relationship: &str) {
+        let source_table: Vec<String> = source_table.iter().map(|s| s.to_string()).collect();
+        let key = relationship_key(&source_table, relationship);
+        assert_eq!(
+            parse_relationship_key(&key),
+            Some((source_table, relationship.to_string())),
+            "key {key}"
+        );
+    }
+
+    #[test]
+    fn round_trips_plain_names() {
+        round_trip(&["Album"], "Artist");
+        round_trip(&["public", "Album"], "Artist");
+    }
+
+    #[test]
+    fn round_trips_names_with_dots_and_backslashes() {
+        round_trip(&["public.Album"], "Artist");
+        round_trip(&["Album"], "the.Artist");
+        round_trip(&["Al\\bum"], "Artist\\");
+        round_trip(&["a\\.b", ".\\"], "\\.");
+    }
+
+    #[test]
+    fn dots_in_names_do_not_collide_with_schema_separators() {
+        assert_ne!(
+            relationship_key(&vec!["public.Album".to_string()], "Artist"),
+            relationship_key(&vec!["public".to_string(), "Album".to_string()], "Artist")
+        );
+    }
+
+    #[test]
+    fn rejects_keys_without_a_source_table() {
+        assert_eq!(parse_relationship_key("Artist"), None);
+        assert_eq!(parse_relationship_key("Album\\"), None);
+    }
+}
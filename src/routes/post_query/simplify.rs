@@ -0,0 +1,289 @@
+use ndc_client::models;
+
+/// An expression after simplification, with constant truth values kept separate so they can be folded into their parents
+enum Simplified {
+    True,
+    False,
+    Expression(models::Expression),
+}
+
+impl Simplified {
+    /// v3 has no boolean literals, so constants are represented by an empty "And" (true) or an empty "Or" (false)
+    fn into_expression(self) -> models::Expression {
+        match self {
+            Self::True => models::Expression::And {
+                expressions: vec![],
+            },
+            Self::False => models::Expression::Or {
+                expressions: vec![],
+            },
+            Self::Expression(expression) => expression,
+        }
+    }
+}
+
+/// Simplify every predicate in a query, including those of nested relationship queries and order by paths.
+/// Predicates that always hold are removed entirely.
+pub fn simplify_query(query: models::Query) -> models::Query {
+    models::Query {
+        fields: query.fields.map(|fields| {
+            fields
+                .into_iter()
+                .map(|(alias, field)| {
+                    (
+                        alias,
+                        match field {
+                            models::Field::Relationship {
+                                query,
+                                relationship,
+                                arguments,
+                            } => models::Field::Relationship {
+                                query: Box::new(simplify_query(*query)),
+                                relationship,
+                                arguments,
+                            },
+                            field => field,
+                        },
+                    )
+                })
+                .collect()
+        }),
+        order_by: query.order_by.map(|order_by| models::OrderBy {
+            elements: order_by
+                .elements
+                .into_iter()
+                .map(simplify_order_by_element)
+                .collect(),
+        }),
+        predicate: query
+            .predicate
+            .and_then(|predicate| match simplify(predicate) {
+                Simplified::True => None,
+                simplified => Some(simplified.into_expression()),
+            }),
+        ..query
+    }
+}
+
+fn simplify_order_by_element(element: models::OrderByElement) -> models::OrderByElement {
+    let target = match element.target {
+        models::OrderByTarget::SingleColumnAggregate {
+            column,
+            function,
+            path,
+        } => models::OrderByTarget::SingleColumnAggregate {
+            column,
+            function,
+            path: simplify_path(path),
+        },
+        models::OrderByTarget::StarCountAggregate { path } => {
+            models::OrderByTarget::StarCountAggregate {
+                path: simplify_path(path),
+            }
+        }
+        target => target,
+    };
+
+    models::OrderByElement { target, ..element }
+}
+
+fn simplify_path(
+    path: Vec<models::PathElementWithPredicate>,
+) -> Vec<models::PathElementWithPredicate> {
+    path.into_iter()
+        .map(|element| models::PathElementWithPredicate {
+            predicate: Box::new(simplify(*element.predicate).into_expression()),
+            ..element
+        })
+        .collect()
+}
+
+/// Flatten nested "And" and "Or" expressions, fold constant branches, and remove double negation.
+/// These rewrites hold under three valued logic, so comparisons against null behave the same before and after.
+fn simplify(expression: models::Expression) -> Simplified {
+    match expression {
+        models::Expression::And { expressions } => {
+            let mut simplified = vec![];
+            for expression in expressions {
+                match simplify(expression) {
+                    Simplified::True => {}
+                    Simplified::False => return Simplified::False,
+                    Simplified::Expression(models::Expression::And { expressions }) => {
+                        simplified.extend(expressions)
+                    }
+                    Simplified::Expression(expression) => simplified.push(expression),
+                }
+            }
+            match simplified.len() {
+                0 => Simplified::True,
+                1 => Simplified::Expression(simplified.remove(0)),
+                _ => Simplified::Expression(models::Expression::And {
+                    expressions: simplified,
+                }),
+            }
+        }
+        models::Expression::Or { expressions } => {
+            let mut simplified = vec![];
+            for expression in expressions {
+                match simplify(expression) {
+                    Simplified::True => return Simplified::True,
+                    Simplified::False => {}
+                    Simplified::Expression(models::Expression::Or { expressions }) => {
+                        simplified.extend(expressions)
+                    }
+                    Simplified::Expression(expression) => simplified.push(expression),
+                }
+            }
+            match simplified.len() {
+                0 => Simplified::False,
+                1 => Simplified::Expression(simplified.remove(0)),
+                _ => Simplified::Expression(models::Expression::Or {
+                    expressions: simplified,
+                }),
+            }
+        }
+        models::Expression::Not { expression } => match simplify(*expression) {
+            Simplified::True => Simplified::False,
+            Simplified::False => Simplified::True,
+            Simplified::Expression(models::Expression::Not { expression }) => {
+                Simplified::Expression(*expression)
+            }
+            Simplified::Expression(expression) => Simplified::Expression(models::Expression::Not {
+                expression: Box::new(expression),
+            }),
+        },
+        models::Expression::Exists {
+            in_table,
+            predicate,
+        } => match simplify(*predicate) {
+            // no row can match, so there is nothing to look for
+            Simplified::False => Simplified::False,
+            predicate => Simplified::Expression(models::Expression::Exists {
+                in_table,
+                predicate: Box::new(predicate.into_expression()),
+            }),
+        },
+        expression => Simplified::Expression(expression),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndc_client::models;
+    use serde_json::Value;
+
+    use super::simplify;
+
+    fn is_null(column: &str) -> models::Expression {
+        models::Expression::UnaryComparisonOperator {
+            column: Box::new(models::ComparisonTarget::RootTableColumn {
+                name: column.to_string(),
+            }),
+            operator: Box::new(models::UnaryComparisonOperator::IsNull),
+        }
+    }
+
+    fn and(expressions: Vec<models::Expression>) -> models::Expression {
+        models::Expression::And { expressions }
+    }
+
+    fn or(expressions: Vec<models::Expression>) -> models::Expression {
+        models::Expression::Or { expressions }
+    }
+
+    fn not(expression: models::Expression) -> models::Expression {
+        models::Expression::Not {
+            expression: Box::new(expression),
+        }
+    }
+
+    fn simplified(expression: models::Expression) -> Value {
+        serde_json::to_value(simplify(expression).into_expression()).unwrap()
+    }
+
+    fn json(expression: models::Expression) -> Value {
+        serde_json::to_value(expression).unwrap()
+    }
+
+    #[test]
+    fn removes_true_branches_of_and() {
+        assert_eq!(
+            simplified(and(vec![and(vec![]), is_null("a")])),
+            json(is_null("a"))
+        );
+    }
+
+    #[test]
+    fn and_with_a_false_branch_is_false() {
+        assert_eq!(
+            simplified(and(vec![is_null("a"), or(vec![])])),
+            json(or(vec![]))
+        );
+    }
+
+    #[test]
+    fn removes_false_branches_of_or() {
+        assert_eq!(
+            simplified(or(vec![or(vec![]), is_null("a")])),
+            json(is_null("a"))
+        );
+    }
+
+    #[test]
+    fn or_with_a_true_branch_is_true() {
+        assert_eq!(
+            simplified(or(vec![is_null("a"), and(vec![])])),
+            json(and(vec![]))
+        );
+    }
+
+    #[test]
+    fn flattens_nested_and_and_or() {
+        assert_eq!(
+            simplified(and(vec![
+                and(vec![is_null("a"), is_null("b")]),
+                is_null("c")
+            ])),
+            json(and(vec![is_null("a"), is_null("b"), is_null("c")]))
+        );
+        assert_eq!(
+            simplified(or(vec![is_null("a"), or(vec![is_null("b"), is_null("c")])])),
+            json(or(vec![is_null("a"), is_null("b"), is_null("c")]))
+        );
+    }
+
+    #[test]
+    fn does_not_flatten_or_into_and() {
+        let expression = || and(vec![is_null("a"), or(vec![is_null("b"), is_null("c")])]);
+        assert_eq!(simplified(expression()), json(expression()));
+    }
+
+    #[test]
+    fn negates_constants() {
+        assert_eq!(simplified(not(and(vec![]))), json(or(vec![])));
+        assert_eq!(simplified(not(or(vec![]))), json(and(vec![])));
+    }
+
+    #[test]
+    fn removes_double_negation() {
+        assert_eq!(simplified(not(not(is_null("a")))), json(is_null("a")));
+    }
+
+    #[test]
+    fn keeps_single_negation() {
+        // under three valued logic, not(a) is unknown when a is, so it cannot be rewritten into anything else
+        assert_eq!(simplified(not(is_null("a"))), json(not(is_null("a"))));
+    }
+
+    #[test]
+    fn exists_with_a_false_predicate_is_false() {
+        let exists = models::Expression::Exists {
+            in_table: Box::new(models::ExistsInTable::Unrelated {
+                table: "albums".to_string(),
+                arguments: Default::default(),
+            }),
+            predicate: Box::new(and(vec![is_null("a"), or(vec![])])),
+        };
+        assert_eq!(simplified(exists), json(or(vec![])));
+    }
+}
@@ -0,0 +1,684 @@
+use indexmap::IndexMap;
+use ndc_client::models;
+use serde_json::Value;
+
+use crate::{
+    aggregates::emulated_aggregate_function,
+    api::{
+        error_response::ErrorResponseType,
+        query_request::{
+            Aggregate, ComparisonColumn, ComparisonValue, ExistsInTable, Expression, Field,
+            OrderBy, OrderByElement, OrderByRelation, OrderByTarget, Query, QueryRequest,
+            TableName, TableRelationships,
+        },
+    },
+    config::Config,
+    error::ServerError,
+    operators::ComparisonOperators,
+    schema::{column_scalar_type, scalar_type_name, table_name, table_type},
+};
+
+use super::relationships::RelationshipLookup;
+
+/// Check a v2 request against the v3 schema before translating it, so problems are reported up front instead of by the connector.
+/// All problems are collected and returned together, each with the JSON path of the offending part of the request.
+pub fn validate_request(
+    request: &QueryRequest,
+    schema: &models::SchemaResponse,
+    config: &Config,
+) -> Result<(), ServerError> {
+    let mut validator = Validator {
+        schema,
+        config,
+        relationships: RelationshipLookup::new(&request.table_relationships),
+        operators: ComparisonOperators::new(schema),
+        errors: vec![],
+    };
+
+    validator.table_relationships(&request.table_relationships, "$.table_relationships");
+
+    if validator.table(&request.table, "$.table") {
+        validator.query(&request.query, &request.table, "$.query");
+
+        for (index, foreach) in request.foreach.iter().flatten().enumerate() {
+            for (column, value) in foreach {
+                validator.column(
+                    &request.table,
+                    column,
+                    Some(value.value_type.as_str()),
+                    &key(&format!("$.foreach[{index}]"), column),
+                );
+            }
+        }
+    }
+
+    if validator.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ServerError::UncaughtError {
+            message: format!(
+                "Query request is not valid for the target schema: found {} problem(s)",
+                validator.errors.len()
+            ),
+            details: Some(serde_json::json!({ "errors": validator.errors })),
+            error_type: ErrorResponseType::UncaughtError,
+        })
+    }
+}
+
+struct Validator<'a> {
+    schema: &'a models::SchemaResponse,
+    config: &'a Config,
+    relationships: RelationshipLookup<'a>,
+    operators: ComparisonOperators,
+    errors: Vec<Value>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, path: &str, message: String) {
+        self.errors
+            .push(serde_json::json!({ "path": path, "message": message }));
+    }
+
+    fn server_error(&mut self, path: &str, error: ServerError) {
+        match error {
+            ServerError::UncaughtError {
+                message, details, ..
+            } => self.errors.push(serde_json::json!({
+                "path": path,
+                "message": message,
+                "details": details,
+            })),
+        }
+    }
+
+    fn object_type(&self, table: &TableName) -> Option<&'a models::ObjectType> {
+        table_type(self.schema, &table_name(table))
+    }
+
+    /// Check the table exists in the schema, returning whether it does
+    fn table(&mut self, table: &TableName, path: &str) -> bool {
+        if self.object_type(table).is_none() {
+            self.error(path, format!("Table {} not found", table.join(".")));
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Check the column exists on the table and is a scalar column, and optionally that it has the expected scalar type
+    fn column(&mut self, table: &TableName, column: &str, expected_type: Option<&str>, path: &str) {
+        let object_type = match self.object_type(table) {
+            Some(object_type) => object_type,
+            // missing tables are reported where the table is referenced
+            None => return,
+        };
+
+        let field_type = match object_type.fields.get(column) {
+            Some(field) => &field.r#type,
+            None => {
+                return self.error(
+                    path,
+                    format!("Column {column} not found on table {}", table.join(".")),
+                )
+            }
+        };
+
+        match (scalar_type_name(field_type), expected_type) {
+            (None, _) => self.error(
+                path,
+                format!(
+                    "Column {column} on table {} is not a scalar column",
+                    table.join(".")
+                ),
+            ),
+            (Some(actual_type), Some(expected_type)) if actual_type != expected_type => self.error(
+                path,
+                format!(
+                    "Column {column} on table {} has type {actual_type}, not {expected_type}",
+                    table.join(".")
+                ),
+            ),
+            _ => {}
+        }
+    }
+
+    fn aggregate_function(
+        &mut self,
+        table: &TableName,
+        column: &str,
+        function: &str,
+        result_type: &str,
+        allow_emulated: bool,
+        path: &str,
+    ) {
+        let scalar_type = match column_scalar_type(self.schema, &table_name(table), column) {
+            Some(scalar_type) => scalar_type,
+            // missing columns are reported by the column check
+            None => return,
+        };
+
+        let schema = self.schema;
+        match schema
+            .scalar_types
+            .get(scalar_type)
+            .and_then(|scalar_type| scalar_type.aggregate_functions.get(function))
+        {
+            None => match emulated_aggregate_function(schema, scalar_type, function) {
+                Some((_, emulated_result_type)) if allow_emulated => {
+                    if self.config.aggregate_emulation.is_none() {
+                        self.error(
+                            path,
+                            format!("Aggregate function {function} is not supported by the target, and aggregate_emulation is not enabled in the source configuration"),
+                        );
+                    } else if emulated_result_type != result_type {
+                        self.error(
+                            path,
+                            format!("Aggregate function {function} on column {column} does not return {result_type}"),
+                        );
+                    }
+                }
+                _ => self.error(
+                    path,
+                    format!("Aggregate function {function} is not supported for column {column} of type {scalar_type}"),
+                ),
+            },
+            Some(aggregate_function) => match scalar_type_name(&aggregate_function.result_type) {
+                Some(actual_type) if actual_type == result_type => {}
+                _ => self.error(
+                    path,
+                    format!("Aggregate function {function} on column {column} does not return {result_type}"),
+                ),
+            },
+        }
+    }
+
+    fn table_relationships(&mut self, table_relationships: &[TableRelationships], path: &str) {
+        for (index, table_relationships) in table_relationships.iter().enumerate() {
+            let path = format!("{path}[{index}]");
+
+            if !self.table(
+                &table_relationships.source_table,
+                &format!("{path}.source_table"),
+            ) {
+                continue;
+            }
+
+            for (name, relationship) in &table_relationships.relationships {
+                let path = key(&format!("{path}.relationships"), name);
+
+                if !self.table(&relationship.target_table, &format!("{path}.target_table")) {
+                    continue;
+                }
+
+                for (source_column, target_column) in &relationship.column_mapping {
+                    let path = key(&format!("{path}.column_mapping"), source_column);
+                    self.column(
+                        &table_relationships.source_table,
+                        source_column,
+                        None,
+                        &path,
+                    );
+                    self.column(&relationship.target_table, target_column, None, &path);
+                }
+            }
+        }
+    }
+
+    fn query(&mut self, query: &Query, table: &TableName, path: &str) {
+        for (alias, field) in query.fields.iter().flatten() {
+            let path = key(&format!("{path}.fields"), alias);
+            match field {
+                Field::Column {
+                    column,
+                    column_type,
+                } => self.column(table, column, Some(column_type.as_str()), &path),
+                Field::Relationship {
+                    query,
+                    relationship,
+                } => match self.relationships.get(table, relationship) {
+                    Ok(relationship) => {
+                        self.query(query, &relationship.target_table, &format!("{path}.query"))
+                    }
+                    Err(error) => self.server_error(&path, error),
+                },
+            }
+        }
+
+        for (alias, aggregate) in query.aggregates.iter().flatten() {
+            let path = key(&format!("{path}.aggregates"), alias);
+            match aggregate {
+                Aggregate::ColumnCount { column, .. } => self.column(table, column, None, &path),
+                Aggregate::SingleColumn {
+                    column,
+                    function,
+                    result_type,
+                } => {
+                    self.column(table, column, None, &path);
+                    self.aggregate_function(table, column, function, result_type, true, &path);
+                }
+                Aggregate::StarCount => {}
+            }
+        }
+
+        if let Some(order_by) = &query.order_by {
+            self.order_by(order_by, table, &format!("{path}.order_by"));
+        }
+
+        if let Some(selection) = &query.selection {
+            self.expression(selection, table, table, &format!("{path}.selection"));
+        }
+    }
+
+    fn order_by(&mut self, order_by: &OrderBy, table: &TableName, path: &str) {
+        self.order_by_relations(&order_by.relations, table, &format!("{path}.relations"));
+
+        for (index, element) in order_by.elements.iter().enumerate() {
+            let path = format!("{path}.elements[{index}]");
+
+            let target_table = match self.order_by_target_table(order_by, element, table, &path) {
+                Some(target_table) => target_table,
+                None => continue,
+            };
+
+            let path = format!("{path}.target");
+            match &element.target {
+                OrderByTarget::Column { column } => self.column(&target_table, column, None, &path),
+                OrderByTarget::SingleColumnAggregate {
+                    column,
+                    function,
+                    result_type,
+                } => {
+                    self.column(&target_table, column, None, &path);
+                    // ordering by emulated aggregates would require fetching every related row, so it is not supported
+                    self.aggregate_function(
+                        &target_table,
+                        column,
+                        function,
+                        result_type,
+                        false,
+                        &path,
+                    );
+                }
+                OrderByTarget::StarCountAggregate => {}
+            }
+        }
+    }
+
+    /// Follow the target path of an order by element through the relations, to find the table the target refers to
+    fn order_by_target_table(
+        &mut self,
+        order_by: &OrderBy,
+        element: &OrderByElement,
+        table: &TableName,
+        path: &str,
+    ) -> Option<TableName> {
+        let mut relations = &order_by.relations;
+        let mut target_table = table.to_owned();
+        for (index, segment) in element.target_path.iter().enumerate() {
+            let relation = match relations.get(segment) {
+                Some(relation) => relation,
+                None => {
+                    self.error(
+                        &format!("{path}.target_path[{index}]"),
+                        format!("Order by path segment {segment} does not reference an order by relation"),
+                    );
+                    return None;
+                }
+            };
+            // invalid relationships are reported when checking the relations
+            target_table = self
+                .relationships
+                .get(&target_table, segment)
+                .ok()?
+                .target_table
+                .to_owned();
+            relations = &relation.subrelations;
+        }
+
+        Some(target_table)
+    }
+
+    fn order_by_relations(
+        &mut self,
+        relations: &IndexMap<String, OrderByRelation>,
+        table: &TableName,
+        path: &str,
+    ) {
+        for (name, relation) in relations {
+            let path = key(path, name);
+            let target_table = match self.relationships.get(table, name) {
+                Ok(relationship) => relationship.target_table.to_owned(),
+                Err(error) => {
+                    self.server_error(&path, error);
+                    continue;
+                }
+            };
+
+            if let Some(selection) = &relation.selection {
+                self.expression(
+                    selection,
+                    &target_table,
+                    &target_table,
+                    &format!("{path}.selection"),
+                );
+            }
+            self.order_by_relations(
+                &relation.subrelations,
+                &target_table,
+                &format!("{path}.subrelations"),
+            );
+        }
+    }
+
+    /// Check an expression, where `root_table` is the table of the query and `table` is the table unqualified columns refer to
+    fn expression(
+        &mut self,
+        expression: &Expression,
+        root_table: &TableName,
+        table: &TableName,
+        path: &str,
+    ) {
+        match expression {
+            Expression::And { expressions } | Expression::Or { expressions } => {
+                for (index, expression) in expressions.iter().enumerate() {
+                    self.expression(
+                        expression,
+                        root_table,
+                        table,
+                        &format!("{path}.expressions[{index}]"),
+                    );
+                }
+            }
+            Expression::Not { expression } => {
+                self.expression(expression, root_table, table, &format!("{path}.expression"))
+            }
+            Expression::UnaryComparisonOperator { column, operator } => {
+                self.comparison_column(column, root_table, table, &format!("{path}.column"));
+                if let Err(error) = self.operators.unary_expression(
+                    &column.column_type,
+                    operator,
+                    placeholder_target(column),
+                ) {
+                    self.server_error(&format!("{path}.operator"), error);
+                }
+            }
+            Expression::BinaryComparisonOperator {
+                column,
+                operator,
+                value,
+            } => {
+                self.comparison_column(column, root_table, table, &format!("{path}.column"));
+                if let Err(error) = self
+                    .operators
+                    .binary_operator(&column.column_type, operator)
+                {
+                    self.server_error(&format!("{path}.operator"), error);
+                }
+                if let ComparisonValue::AnotherColumnComparison { column } = value {
+                    self.comparison_column(
+                        column,
+                        root_table,
+                        table,
+                        &format!("{path}.value.column"),
+                    );
+                }
+            }
+            Expression::BinaryArrayComparisonOperator {
+                column,
+                operator,
+                values,
+                ..
+            } => {
+                self.comparison_column(column, root_table, table, &format!("{path}.column"));
+                if let Err(error) = self.operators.array_expression(
+                    &column.column_type,
+                    operator,
+                    placeholder_target(column),
+                    values.to_owned(),
+                ) {
+                    self.server_error(&format!("{path}.operator"), error);
+                }
+            }
+            Expression::Exists {
+                in_table,
+                selection,
+            } => {
+                let exists_table = match in_table {
+                    ExistsInTable::UnrelatedTable { table } => {
+                        if !self.table(table, &format!("{path}.in_table.table")) {
+                            return;
+                        }
+                        table.to_owned()
+                    }
+                    ExistsInTable::RelatedTable { relationship } => {
+                        match self.relationships.get(table, relationship) {
+                            Ok(relationship) => relationship.target_table.to_owned(),
+                            Err(error) => {
+                                return self
+                                    .server_error(&format!("{path}.in_table.relationship"), error)
+                            }
+                        }
+                    }
+                };
+
+                self.expression(
+                    selection,
+                    root_table,
+                    &exists_table,
+                    &format!("{path}.selection"),
+                );
+            }
+        }
+    }
+
+    fn comparison_column(
+        &mut self,
+        column: &ComparisonColumn,
+        root_table: &TableName,
+        table: &TableName,
+        path: &str,
+    ) {
+        let column_table = match column.path.as_deref().unwrap_or_default() {
+            [] => table.to_owned(),
+            [root] if root == "$" => root_table.to_owned(),
+            [root, ..] if root == "$" => {
+                return self.error(
+                    &format!("{path}.path"),
+                    "Comparison column paths starting at the root table cannot be followed by relationships".to_string(),
+                )
+            }
+            relationships => {
+                let mut column_table = table.to_owned();
+                for (index, relationship) in relationships.iter().enumerate() {
+                    match self.relationships.get(&column_table, relationship) {
+                        Ok(relationship) => column_table = relationship.target_table.to_owned(),
+                        Err(error) => {
+                            return self.server_error(&format!("{path}.path[{index}]"), error)
+                        }
+                    }
+                }
+                column_table
+            }
+        };
+
+        self.column(
+            &column_table,
+            &column.name,
+            Some(column.column_type.as_str()),
+            &format!("{path}.name"),
+        );
+    }
+}
+
+/// Operator mapping needs a comparison target, but only the operator and scalar type are relevant to validation
+fn placeholder_target(column: &ComparisonColumn) -> models::ComparisonTarget {
+    models::ComparisonTarget::RootTableColumn {
+        name: column.name.to_owned(),
+    }
+}
+
+/// Append a key to a JSON path, using bracket notation for keys that are not plain identifiers
+fn key(path: &str, key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        format!("{path}.{key}")
+    } else {
+        format!("{path}[{}]", Value::String(key.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::validate_request;
+    use crate::{api::query_request::QueryRequest, config::Config, error::ServerError, fixtures};
+
+    fn validate(table: Value, query: Value, config: Value) -> Result<(), Vec<Value>> {
+        let request: QueryRequest = serde_json::from_value(json!({
+            "table": table,
+            "query": query,
+            "table_relationships": fixtures::table_relationships(),
+        }))
+        .unwrap();
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        validate_request(&request, &fixtures::schema(), &config).map_err(|error| {
+            let ServerError::UncaughtError { details, .. } = error;
+            details.unwrap()["errors"].as_array().unwrap().to_owned()
+        })
+    }
+
+    fn paths(errors: &[Value]) -> Vec<&str> {
+        errors
+            .iter()
+            .map(|error| error["path"].as_str().unwrap())
+            .collect()
+    }
+
+    fn column(column: &str, column_type: &str) -> Value {
+        json!({ "type": "column", "column": column, "column_type": column_type })
+    }
+
+    #[test]
+    fn accepts_valid_requests() {
+        let query = json!({
+            "fields": {
+                "Title": column("Title", "String"),
+                "Artist": {
+                    "type": "relationship",
+                    "relationship": "Artist",
+                    "query": { "fields": { "Name": column("Name", "String") } },
+                },
+            },
+            "aggregates": {
+                "max_id": {
+                    "type": "single_column",
+                    "column": "AlbumId",
+                    "function": "max",
+                    "result_type": "Int32",
+                },
+            },
+        });
+        assert!(validate(json!(["Album"]), query, json!({})).is_ok());
+    }
+
+    #[test]
+    fn collects_every_error_with_its_path() {
+        let query = json!({
+            "fields": {
+                "Missing": column("Missing", "String"),
+                "Label": {
+                    "type": "relationship",
+                    "relationship": "Label",
+                    "query": { "fields": {} },
+                },
+            },
+            "aggregates": {
+                "median": {
+                    "type": "single_column",
+                    "column": "AlbumId",
+                    "function": "median",
+                    "result_type": "Int32",
+                },
+            },
+        });
+        let errors = validate(json!(["Album"]), query, json!({})).unwrap_err();
+
+        assert_eq!(
+            paths(&errors),
+            vec![
+                "$.query.fields.Missing",
+                "$.query.fields.Label",
+                "$.query.aggregates.median",
+            ]
+        );
+        assert_eq!(
+            errors[0]["message"],
+            "Column Missing not found on table Album"
+        );
+        assert_eq!(errors[1]["details"]["relationship"], "Label");
+        assert_eq!(
+            errors[2]["message"],
+            "Aggregate function median is not supported for column AlbumId of type Int32"
+        );
+    }
+
+    #[test]
+    fn reports_errors_in_nested_queries_and_predicates() {
+        let query = json!({
+            "fields": {
+                "Artist": {
+                    "type": "relationship",
+                    "relationship": "Artist",
+                    "query": { "fields": { "the name": column("Name", "Int32") } },
+                },
+            },
+            "where": {
+                "type": "exists",
+                "in_table": { "type": "related", "relationship": "Tracks" },
+                "where": { "type": "and", "expressions": [] },
+            },
+        });
+        let errors = validate(json!(["Album"]), query, json!({})).unwrap_err();
+
+        assert_eq!(
+            paths(&errors),
+            vec![
+                "$.query.fields.Artist.query.fields[\"the name\"]",
+                "$.query.selection.in_table.relationship",
+            ]
+        );
+        assert_eq!(
+            errors[0]["message"],
+            "Column Name on table Artist has type String, not Int32"
+        );
+    }
+
+    #[test]
+    fn reports_emulated_aggregates_when_emulation_is_disabled() {
+        let query = json!({
+            "aggregates": {
+                "average": {
+                    "type": "single_column",
+                    "column": "AlbumId",
+                    "function": "avg",
+                    "result_type": "Float64",
+                },
+            },
+        });
+
+        let errors = validate(json!(["Album"]), query.clone(), json!({})).unwrap_err();
+        assert_eq!(paths(&errors), vec!["$.query.aggregates.average"]);
+
+        let config = json!({ "aggregate_emulation": { "max_rows": 100 } });
+        assert!(validate(json!(["Album"]), query, config).is_ok());
+    }
+
+    #[test]
+    fn looks_up_namespaced_tables_by_their_joined_name() {
+        let errors = validate(json!(["public", "Album"]), json!({}), json!({})).unwrap_err();
+        assert_eq!(paths(&errors), vec!["$.table"]);
+        assert_eq!(errors[0]["message"], "Table public.Album not found");
+    }
+}
@@ -9,9 +9,10 @@ use crate::{
     api::{raw_request::RawRequest, raw_response::RawResponse},
     config::{ProxyTarget, SourceConfig, SourceName},
     error::ServerError,
+    state::AppState,
 };
 
-#[axum_macros::debug_handler]
+#[axum_macros::debug_handler(state = AppState)]
 pub async fn post_raw(
     ProxyTarget(base_url): ProxyTarget,
     SourceName(_source_name): SourceName,
@@ -0,0 +1,43 @@
+//! Lookups in the schema of a v3 target, shared by query validation and mapping, aggregate emulation, and capabilities
+
+use ndc_client::models;
+
+use crate::api::query_request::TableName;
+
+/// The name of the v3 collection for a v2 table name.
+/// Tables are advertised to HGE with the collection name as their only component, so the components of namespaced names are joined with dots.
+pub fn table_name(table: &TableName) -> String {
+    table.join(".")
+}
+
+/// The object type of the rows of a collection
+pub fn table_type<'a>(
+    schema: &'a models::SchemaResponse,
+    table: &str,
+) -> Option<&'a models::ObjectType> {
+    schema
+        .tables
+        .iter()
+        .find(|table_info| table_info.name == table)
+        .and_then(|table_info| schema.object_types.get(&table_info.table_type))
+}
+
+/// The scalar type of a column of a collection, if the column exists and has a scalar type
+pub fn column_scalar_type<'a>(
+    schema: &'a models::SchemaResponse,
+    table: &str,
+    column: &str,
+) -> Option<&'a str> {
+    table_type(schema, table)
+        .and_then(|object_type| object_type.fields.get(column))
+        .and_then(|field| scalar_type_name(&field.r#type))
+}
+
+/// The name of a scalar type, which may be nullable. Arrays have no v2 equivalent.
+pub fn scalar_type_name(r#type: &models::Type) -> Option<&str> {
+    match r#type {
+        models::Type::Named { name } => Some(name),
+        models::Type::Nullable { underlying_type } => scalar_type_name(underlying_type),
+        models::Type::Array { .. } => None,
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    error::Error,
+    future::{pending, Future},
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use tokio::{
+    net::UnixListener,
+    signal::unix::{signal, SignalKind},
+    sync::oneshot,
+};
+
+/// Where the proxy accepts connections
+pub enum Listener {
+    Tcp(SocketAddr),
+    Tls(SocketAddr, RustlsConfig),
+    Unix(PathBuf),
+}
+
+/// Serve the router until the process is asked to stop.
+/// Requests in flight are then given until the shutdown timeout to complete, and dropped after it.
+pub async fn serve(
+    router: Router,
+    listener: Listener,
+    shutdown_timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    match listener {
+        Listener::Tcp(address) => {
+            println!("Starting server on {}", address);
+            let (stopping, stopped) = oneshot::channel();
+            let server = axum::Server::bind(&address)
+                .serve(router.into_make_service())
+                .with_graceful_shutdown(stop_signal(stopping));
+            with_deadline(server, stopped, shutdown_timeout).await?;
+        }
+        Listener::Tls(address, tls_config) => {
+            println!("Starting server on {} with TLS", address);
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal().await;
+                    handle.graceful_shutdown(Some(shutdown_timeout));
+                }
+            });
+            axum_server::bind_rustls(address, tls_config)
+                .handle(handle)
+                .serve(router.into_make_service())
+                .await?;
+        }
+        Listener::Unix(path) => {
+            remove_socket(&path)?;
+            let listener = UnixListener::bind(&path)?;
+            println!("Starting server on {}", path.display());
+            let accept = hyper::server::accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|connection| Some(connection.map(|(stream, _)| stream)))
+            });
+            let (stopping, stopped) = oneshot::channel();
+            let server = axum::Server::builder(accept)
+                .serve(router.into_make_service())
+                .with_graceful_shutdown(stop_signal(stopping));
+            let result = with_deadline(server, stopped, shutdown_timeout).await;
+            remove_socket(&path)?;
+            result?;
+        }
+    }
+
+    println!("Server stopped");
+
+    Ok(())
+}
+
+/// Resolves on the shutdown signal, telling the deadline it has started
+async fn stop_signal(stopping: oneshot::Sender<()>) {
+    shutdown_signal().await;
+    let _ = stopping.send(());
+}
+
+/// Run a server with graceful shutdown, giving up on open connections once the timeout has passed after the shutdown signal
+async fn with_deadline(
+    server: impl Future<Output = Result<(), hyper::Error>>,
+    stopped: oneshot::Receiver<()>,
+    shutdown_timeout: Duration,
+) -> Result<(), hyper::Error> {
+    let deadline = async {
+        match stopped.await {
+            Ok(()) => tokio::time::sleep(shutdown_timeout).await,
+            // the server finished without being asked to stop
+            Err(_) => pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = server => result,
+        () = deadline => {
+            eprintln!("Requests still in flight after {shutdown_timeout:?}, stopping anyway");
+            Ok(())
+        }
+    }
+}
+
+/// Resolves when the process receives SIGTERM, as sent by Kubernetes before stopping a pod, or Ctrl-C
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                eprintln!("Unable to listen for SIGTERM: {err}");
+                pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        () = terminate => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+
+    println!("Shutting down");
+}
+
+/// Remove a socket file left behind by a previous run, which would stop the listener from binding.
+/// Files other than sockets are left alone, so a mistyped path cannot delete them.
+fn remove_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,44 @@
+use std::{sync::Arc, time::Duration};
+
+use jsonschema::JSONSchema;
+
+use crate::{
+    allowlist::TargetAllowlist,
+    cache::{QueryCache, SchemaCache},
+    config::Config,
+    registry::TargetRegistry,
+};
+
+/// Server wide settings, shared by all request handlers
+#[derive(Clone)]
+pub struct AppState {
+    /// The maximum size in bytes of a query response read from a target
+    pub max_response_size: usize,
+    /// Query responses cached for sources with caching enabled
+    pub query_cache: Arc<QueryCache>,
+    /// Target capabilities and schemas, shared by all sources
+    pub schema_cache: Arc<SchemaCache>,
+    /// Targets and sources defined in the config file, if one was given
+    pub registry: Option<Arc<TargetRegistry>>,
+    /// The targets requests may be sent to
+    pub target_allowlist: Arc<TargetAllowlist>,
+    /// How long health checks wait for the target
+    pub health_check_timeout: Duration,
+    /// The advertised config schema, which config headers are validated against
+    pub config_schema: Arc<JSONSchema>,
+    /// Prefixes of the environment variables that configs sent by HGE may read secrets from
+    pub secret_env_prefixes: Arc<[String]>,
+}
+
+impl AppState {
+    /// The maximum size of a response from the target of a source, which may lower the server wide maximum
+    pub fn max_response_size(&self, config: &Config) -> usize {
+        config
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.max_response_size)
+            .map_or(self.max_response_size, |max_response_size| {
+                max_response_size.min(self.max_response_size)
+            })
+    }
+}
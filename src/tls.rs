@@ -0,0 +1,121 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore};
+
+/// How often certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificate files for the proxy's own listener
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    /// Clients must present a certificate signed by this CA, if set
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    pub fn load(&self) -> io::Result<RustlsConfig> {
+        Ok(RustlsConfig::from_config(Arc::new(self.server_config()?)))
+    }
+
+    fn server_config(&self) -> io::Result<rustls::ServerConfig> {
+        let certificates = read_certificates(&self.certificate)?;
+        let private_key = read_private_key(&self.private_key)?;
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in read_certificates(client_ca)? {
+                    roots
+                        .add(&certificate)
+                        .map_err(|err| invalid(client_ca, err))?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certificates, private_key)
+            .map_err(|err| invalid(&self.certificate, err))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.certificate),
+            Some(&self.private_key),
+            self.client_ca.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+    }
+}
+
+/// Reload certificates when their files change, so they can be rotated without a restart.
+/// If the new files are invalid, for example while only some of them have been replaced, the current certificates stay in use.
+pub async fn reload_on_change(config: RustlsConfig, files: TlsFiles) {
+    let mut modified = files.modified();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let current = files.modified();
+        if current == modified {
+            continue;
+        }
+
+        match files.server_config() {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                modified = current;
+                println!("Reloaded TLS certificates");
+            }
+            Err(err) => eprintln!("Unable to reload TLS certificates: {err}"),
+        }
+    }
+}
+
+fn read_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certificates.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid(path, "no private key found"))
+}
+
+fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
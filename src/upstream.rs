@@ -0,0 +1,327 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{redirect::Policy, Identity, RequestBuilder};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    allowlist::{AllowlistResolver, TargetAllowlist},
+    api::error_response::ErrorResponseType,
+    cache::{hash_bytes, CacheKey, SchemaCache, TargetSchema},
+    config::{Config, UpstreamAuth},
+    error::ServerError,
+};
+
+/// Headers that carry credentials when forwarded, so responses always vary with them
+const CREDENTIAL_HEADERS: [HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+];
+
+/// HTTP client for requests to a v3 target, sending the credentials configured for the source with every request
+pub struct UpstreamClient {
+    client: reqwest::Client,
+    basic_auth: Option<(String, String)>,
+    /// A digest of the resolved credentials, so responses fetched with different credentials are cached separately
+    credentials: CacheKey,
+}
+
+impl UpstreamClient {
+    /// A client for the source with this configuration, or an unauthenticated client when there is none.
+    /// Headers of the incoming request are forwarded if the configuration allows them.
+    /// Secrets are resolved here, so a missing environment variable fails the request rather than sending it without credentials.
+    pub fn new(
+        config: Option<&Config>,
+        incoming_headers: &HeaderMap,
+        target_allowlist: &Arc<TargetAllowlist>,
+    ) -> Result<Self, ServerError> {
+        // redirects could lead to targets outside the allowlist
+        let mut builder = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .dns_resolver(Arc::new(AllowlistResolver(target_allowlist.clone())));
+
+        let config = match config {
+            Some(config) => config,
+            None => {
+                return Ok(Self {
+                    client: builder.build()?,
+                    basic_auth: None,
+                    credentials: [0; 32],
+                })
+            }
+        };
+
+        let mut hasher = Sha256::new();
+        if let Some(timeout_seconds) = config.timeout_seconds {
+            builder = builder.timeout(Duration::from_secs(timeout_seconds));
+        }
+
+        let mut headers = forwarded_headers(config, incoming_headers);
+        let mut credential_headers = CREDENTIAL_HEADERS.to_vec();
+        credential_headers.extend(
+            config
+                .cache_vary_headers
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok()),
+        );
+        let mut basic_auth = None;
+
+        if let Some(auth) = &config.auth {
+            let auth_headers = auth_headers(auth)?;
+            credential_headers.extend(auth_headers.keys().cloned());
+            headers.extend(auth_headers);
+
+            basic_auth = auth
+                .basic
+                .as_ref()
+                .map(|basic| {
+                    Ok::<_, ServerError>((basic.username.resolve()?, basic.password.resolve()?))
+                })
+                .transpose()?;
+
+            if let Some(client_certificate) = &auth.client_certificate {
+                let certificate = client_certificate.certificate.resolve()?;
+                let private_key = client_certificate.private_key.resolve()?;
+                hasher.update(b"c");
+                hash_bytes(certificate.as_bytes(), &mut hasher);
+                let identity =
+                    Identity::from_pkcs8_pem(certificate.as_bytes(), private_key.as_bytes())
+                        .map_err(|err| ServerError::UncaughtError {
+                            details: None,
+                            message: format!("Unable to load client certificate: {err}"),
+                            error_type: ErrorResponseType::UncaughtError,
+                        })?;
+                builder = builder.identity(identity);
+            }
+        }
+
+        // other forwarded headers, such as tracing headers, would give every request its own cache entry
+        for (name, value) in headers
+            .iter()
+            .filter(|(name, _)| credential_headers.contains(name))
+        {
+            hasher.update(b"h");
+            hash_bytes(name.as_str().as_bytes(), &mut hasher);
+            hash_bytes(value.as_bytes(), &mut hasher);
+        }
+        if let Some((username, password)) = &basic_auth {
+            hasher.update(b"b");
+            hash_bytes(username.as_bytes(), &mut hasher);
+            hash_bytes(password.as_bytes(), &mut hasher);
+        }
+        builder = builder.default_headers(headers);
+
+        Ok(Self {
+            client: builder.build()?,
+            basic_auth,
+            credentials: hasher.finalize().into(),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.authorize(self.client.get(url))
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.authorize(self.client.post(url))
+    }
+
+    /// Fetch and parse a JSON response, refusing responses larger than the maximum size
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        max_response_size: usize,
+    ) -> Result<T, ServerError> {
+        let response = self.get(url).send().await?;
+        let body = read_body(response, max_response_size).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// The capabilities and schema of the target, from the cache if they were fetched recently with the same credentials
+    pub async fn target_schema(
+        &self,
+        base_url: &str,
+        cache: &SchemaCache,
+        max_response_size: usize,
+    ) -> Result<Arc<TargetSchema>, ServerError> {
+        if let Some(target_schema) = cache.get(base_url, &self.credentials) {
+            return Ok(target_schema);
+        }
+
+        let target_schema = Arc::new(TargetSchema {
+            capabilities: self
+                .get_json(&format!("{}/capabilities", base_url), max_response_size)
+                .await?,
+            schema: self
+                .get_json(&format!("{}/schema", base_url), max_response_size)
+                .await?,
+        });
+        cache.insert(base_url, &self.credentials, target_schema.clone());
+
+        Ok(target_schema)
+    }
+
+    /// Identifies the credentials of this client, without revealing them
+    pub fn credentials(&self) -> &CacheKey {
+        &self.credentials
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.basic_auth {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        }
+    }
+}
+
+/// Read a response body, failing as soon as it exceeds the maximum size rather than buffering all of it
+pub async fn read_body(
+    mut response: reqwest::Response,
+    max_response_size: usize,
+) -> Result<Vec<u8>, ServerError> {
+    let response_too_large = || ServerError::UncaughtError {
+        details: Some(serde_json::json!({ "max_response_size": max_response_size })),
+        message: format!(
+            "Response from the target exceeded the maximum size of {max_response_size} bytes"
+        ),
+        error_type: ErrorResponseType::UncaughtError,
+    };
+
+    let content_length = response.content_length().unwrap_or(0);
+    if content_length > max_response_size as u64 {
+        return Err(response_too_large());
+    }
+
+    let mut body = Vec::with_capacity(content_length as usize);
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_response_size {
+            return Err(response_too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// The headers of the incoming request that the configuration allows to be forwarded
+fn forwarded_headers(config: &Config, incoming_headers: &HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    for name in &config.forward_headers {
+        let name = match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        for value in incoming_headers.get_all(&name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    headers
+}
+
+fn auth_headers(auth: &UpstreamAuth) -> Result<HeaderMap, ServerError> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in &auth.headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid_header(name))?;
+        let value = header_value(&value.resolve()?, name.as_str())?;
+        headers.insert(name, value);
+    }
+
+    if let Some(bearer_token) = &auth.bearer_token {
+        let value = header_value(
+            &format!("Bearer {}", bearer_token.resolve()?),
+            header::AUTHORIZATION.as_str(),
+        )?;
+        headers.insert(header::AUTHORIZATION, value);
+    }
+
+    Ok(headers)
+}
+
+fn header_value(value: &str, name: &str) -> Result<HeaderValue, ServerError> {
+    let mut value = HeaderValue::from_str(value).map_err(|_| invalid_header(name))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+fn invalid_header(name: &str) -> ServerError {
+    ServerError::UncaughtError {
+        details: Some(serde_json::json!({ "header": name })),
+        message: format!("Invalid upstream header {name} in the source configuration"),
+        error_type: ErrorResponseType::UncaughtError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::{HeaderMap, HeaderValue};
+    use serde_json::json;
+
+    use super::UpstreamClient;
+    use crate::{allowlist::TargetAllowlist, cache::QueryCache, config::Config};
+
+    fn client(config: &Config, headers: &[(&'static str, &'static str)]) -> UpstreamClient {
+        let allowlist = Arc::new(TargetAllowlist {
+            schemes: vec!["http".to_owned()],
+            hosts: vec![],
+            networks: vec![],
+        });
+        let mut incoming_headers = HeaderMap::new();
+        for (name, value) in headers {
+            incoming_headers.insert(*name, HeaderValue::from_static(value));
+        }
+        UpstreamClient::new(Some(config), &incoming_headers, &allowlist).unwrap()
+    }
+
+    fn config(cache_vary_headers: &[&str]) -> Config {
+        serde_json::from_value(json!({
+            "target_url": "http://localhost:8100",
+            "forward_headers": ["traceparent", "x-hasura-role", "authorization"],
+            "cache_vary_headers": cache_vary_headers,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tracing_headers_share_cache_entries() {
+        let config = config(&[]);
+        let first = client(&config, &[("traceparent", "00-aaaa-01-01")]);
+        let second = client(&config, &[("traceparent", "00-bbbb-02-01")]);
+        assert_eq!(first.credentials(), second.credentials());
+
+        let request = json!({ "collection": "albums" });
+        let url = "http://localhost:8100/query";
+        assert_eq!(
+            QueryCache::key(url, first.credentials(), &request).unwrap(),
+            QueryCache::key(url, second.credentials(), &request).unwrap()
+        );
+    }
+
+    #[test]
+    fn forwarded_credentials_vary_cache_entries() {
+        let config = config(&[]);
+        let first = client(&config, &[("authorization", "Bearer a")]);
+        let second = client(&config, &[("authorization", "Bearer b")]);
+        assert_ne!(first.credentials(), second.credentials());
+    }
+
+    #[test]
+    fn configured_headers_vary_cache_entries() {
+        let listed = config(&["X-Hasura-Role"]);
+        let user = client(&listed, &[("x-hasura-role", "user")]);
+        let admin = client(&listed, &[("x-hasura-role", "admin")]);
+        assert_ne!(user.credentials(), admin.credentials());
+
+        let unlisted = config(&[]);
+        assert_eq!(
+            client(&unlisted, &[("x-hasura-role", "user")]).credentials(),
+            client(&unlisted, &[("x-hasura-role", "admin")]).credentials()
+        );
+    }
+}